@@ -0,0 +1,369 @@
+use std::{
+    ffi::{OsStr, OsString},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    apply_diff, entire, find_leaders,
+    ftree::{Fnode, FnodeDir},
+    plan_apply_dir, plan_remove_dir, remove_diff, scan_tree, Conflict, ConflictPolicy, Context,
+    Limits, Resolution, SyncError, SyncEvent, SyncOptions, SyncReport,
+};
+
+/// Name of the directory, at the root of both replicas, holding for each peer the entries
+/// both held in common after their last sync.
+pub const STATE_DIR: &str = ".arsync-state";
+
+/// What has to be added to and removed from each replica.
+#[derive(Default)]
+struct BidiDiff {
+    src_add: FnodeDir,
+    src_rem: FnodeDir,
+    dest_add: FnodeDir,
+    dest_rem: FnodeDir,
+    /// destination paths moved aside to keep both versions of a conflict, applied first
+    renames: Vec<(PathBuf, PathBuf)>,
+}
+
+impl BidiDiff {
+    fn append_dir(&mut self, name: &OsStr, sub: BidiDiff) {
+        self.src_add.append_dir(name.to_os_string(), sub.src_add);
+        self.src_rem.append_dir(name.to_os_string(), sub.src_rem);
+        self.dest_add.append_dir(name.to_os_string(), sub.dest_add);
+        self.dest_rem.append_dir(name.to_os_string(), sub.dest_rem);
+        self.renames.extend(sub.renames);
+    }
+}
+
+enum Side {
+    Src,
+    Dest,
+    Both(OsString),
+}
+
+/// Decides conflicts according to the policy and keeps track of them.
+struct Resolver {
+    policy: ConflictPolicy,
+    /// `<host>-<time>`, appended to the names of conflict copies
+    suffix: String,
+    conflicts: Vec<Conflict>,
+}
+
+impl Resolver {
+    fn new(policy: ConflictPolicy) -> Resolver {
+        let host = gethostname::gethostname();
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Resolver {
+            policy,
+            suffix: format!("{}-{}", host.to_string_lossy(), time),
+            conflicts: vec![],
+        }
+    }
+
+    fn resolve(
+        &mut self,
+        rel: &Path,
+        name: &OsStr,
+        src: Option<&Arc<Fnode>>,
+        dest: Option<&Arc<Fnode>>,
+    ) -> Side {
+        let side = match self.policy {
+            ConflictPolicy::Source => Side::Src,
+            ConflictPolicy::KeepBoth if src.is_some() && dest.is_some() => {
+                let mut copy = name.to_os_string();
+                copy.push(format!(".conflict-{}", self.suffix));
+                Side::Both(copy)
+            }
+            _ => newest(src, dest),
+        };
+        let resolution = match (&side, self.policy) {
+            (_, ConflictPolicy::Abort) => Resolution::Abort,
+            (Side::Src, _) => Resolution::Source,
+            (Side::Dest, _) => Resolution::Destination,
+            (Side::Both(copy), _) => Resolution::KeepBoth(rel.join(copy)),
+        };
+        self.conflicts.push(Conflict {
+            path: rel.join(name),
+            resolution,
+        });
+        side
+    }
+}
+
+fn changed(now: Option<&Arc<Fnode>>, base: Option<&Arc<Fnode>>) -> bool {
+    match (now, base) {
+        (Some(now), Some(base)) => !now.same_as(base),
+        (None, None) => false,
+        _ => true,
+    }
+}
+
+/// Pick the side whose version of a path changed on both replicas:
+/// the most recently modified one, and never a deletion over a modification.
+fn newest(src: Option<&Arc<Fnode>>, dest: Option<&Arc<Fnode>>) -> Side {
+    match (src, dest) {
+        (Some(s), Some(d)) if d.mtime() > s.mtime() => Side::Dest,
+        (None, Some(_)) => Side::Dest,
+        _ => Side::Src,
+    }
+}
+
+/// Turn `old` in the losing replica into `new` from the winning one.
+/// Either may be absent, for a path created or deleted on the winning side.
+fn replace(
+    add: &mut FnodeDir,
+    rem: &mut FnodeDir,
+    name: &OsStr,
+    new: Option<&Arc<Fnode>>,
+    old: Option<&Arc<Fnode>>,
+) {
+    if let (Some(new), Some(old)) = (new, old) {
+        if new.same_kind(old) {
+            add.append(name.to_os_string(), new.clone());
+            return;
+        }
+    }
+    if let Some(old) = old {
+        rem.append(name.to_os_string(), entire(old));
+    }
+    if let Some(new) = new {
+        add.append(name.to_os_string(), entire(new));
+    }
+}
+
+fn subdir(node: Option<&Arc<Fnode>>) -> Option<&FnodeDir> {
+    match node.map(|n| n.as_ref()) {
+        Some(Fnode::Dir(d)) => Some(d),
+        _ => None,
+    }
+}
+
+/// Three-way diff of both replicas against their state after the last sync.
+/// A path changed on one side only is propagated to the other, including deletions,
+/// and one changed on both is a conflict left to `resolver`.
+fn calc_diff_bidi(
+    src: &FnodeDir,
+    dest: &FnodeDir,
+    src_base: &FnodeDir,
+    dest_base: &FnodeDir,
+    rel: &Path,
+    resolver: &mut Resolver,
+) -> BidiDiff {
+    let mut diff = BidiDiff::default();
+    let empty = FnodeDir::default();
+    for (n, s, d) in src.merge(dest) {
+        // what could not be read on one side is left alone on both
+        if src.skipped(n) || dest.skipped(n) {
+            continue;
+        }
+        let (bs, bd) = (src_base.get(n), dest_base.get(n));
+        if let (Some(src_sub), Some(dest_sub)) = (subdir(s), subdir(d)) {
+            let src_base = subdir(bs).unwrap_or(&empty);
+            let dest_base = subdir(bd).unwrap_or(&empty);
+            let sub = calc_diff_bidi(
+                src_sub,
+                dest_sub,
+                src_base,
+                dest_base,
+                &rel.join(n),
+                resolver,
+            );
+            diff.append_dir(n, sub);
+            continue;
+        }
+        let winner = match (changed(s, bs), changed(d, bd)) {
+            (false, false) => continue,
+            (true, false) => Side::Src,
+            (false, true) => Side::Dest,
+            // the same change made on both sides
+            (true, true) if matches!((s, d), (Some(s), Some(d)) if s.same_content(d)) => continue,
+            (true, true) => resolver.resolve(rel, n, s, d),
+        };
+        match winner {
+            Side::Src => replace(&mut diff.dest_add, &mut diff.dest_rem, n, s, d),
+            Side::Dest => replace(&mut diff.src_add, &mut diff.src_rem, n, d, s),
+            Side::Both(copy) => {
+                diff.renames.push((rel.join(n), rel.join(&copy)));
+                if let (Some(s), Some(d)) = (s, d) {
+                    diff.dest_add.append(n.clone(), entire(s));
+                    diff.src_add.append(copy, entire(d));
+                }
+            }
+        }
+    }
+    diff
+}
+
+/// Path of the state of `root` as of its last sync with `peer`. Each replica keeps one
+/// state per peer, so that syncing it with several others does not mix them up.
+fn state_path(root: &Path, peer: &Path) -> PathBuf {
+    let peer = std::fs::canonicalize(peer).unwrap_or_else(|_| peer.to_path_buf());
+    let key = blake3::hash(peer.as_os_str().as_bytes()).to_hex();
+    root.join(STATE_DIR).join(&key[..16])
+}
+
+fn load_state(root: &Path, peer: &Path) -> FnodeDir {
+    std::fs::read(state_path(root, peer))
+        .ok()
+        .and_then(|bytes| bincode::deserialize(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn save_state(root: &Path, peer: &Path, tree: &FnodeDir) -> io::Result<()> {
+    let bytes = bincode::serialize(tree).map_err(io::Error::other)?;
+    let path = state_path(root, peer);
+    std::fs::create_dir_all(root.join(STATE_DIR))?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(tmp, path)
+}
+
+/// The entries of `tree` holding the same content in `other`, which both replicas agree on.
+fn common(tree: &FnodeDir, other: &FnodeDir) -> FnodeDir {
+    let mut agreed = FnodeDir::default();
+    agreed.set_attrs(tree.attrs());
+    for (n, t, o) in tree.merge(other) {
+        if let (Some(t), Some(o)) = (t, o) {
+            match (t.as_ref(), o.as_ref()) {
+                (Fnode::Dir(t), Fnode::Dir(o)) => agreed.append_dir(n.clone(), common(t, o)),
+                _ if t.same_content(o) => agreed.append(n.clone(), t.clone()),
+                _ => {}
+            }
+        }
+    }
+    agreed
+}
+
+/// Scan `root`, synced to `dest`, again once the sync is done.
+async fn rescan(
+    root: &Path,
+    dest: &Path,
+    ignore: Option<&[u8]>,
+    options: &SyncOptions,
+) -> io::Result<FnodeDir> {
+    let (tree, _) = scan_tree(
+        root,
+        dest,
+        ignore,
+        options.links,
+        Limits::default(),
+        options,
+    )
+    .await
+    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "cannot scan directory"))?;
+    Ok(tree)
+}
+
+/// Rescan both replicas and record, for each, what it now has in common with the other
+/// as the state the next sync between them compares against. A path that failed to sync
+/// still differs from that state, so the next sync tries again.
+async fn record_state(
+    src: &Path,
+    dest: &Path,
+    ignores: (Option<&[u8]>, Option<&[u8]>),
+    options: &SyncOptions,
+    report: &mut SyncReport,
+) {
+    let (src_tree, dest_tree) = tokio::join!(
+        rescan(src, dest, ignores.0, options),
+        rescan(dest, dest, ignores.1, options)
+    );
+    let (src_tree, dest_tree) = match (src_tree, dest_tree) {
+        (Ok(src_tree), Ok(dest_tree)) => (src_tree, dest_tree),
+        (Err(e), _) => return report.fail(state_path(src, dest), e),
+        (_, Err(e)) => return report.fail(state_path(dest, src), e),
+    };
+    for (root, peer, tree, other) in [
+        (src, dest, &src_tree, &dest_tree),
+        (dest, src, &dest_tree, &src_tree),
+    ] {
+        if let Err(e) = save_state(root, peer, &common(tree, other)) {
+            report.fail(state_path(root, peer), e);
+        }
+    }
+}
+
+pub(crate) async fn sync_bidi(
+    src: &Path,
+    dest: &Path,
+    src_tree: FnodeDir,
+    dest_tree: FnodeDir,
+    src_ignore: Option<&[u8]>,
+    dest_ignore: Option<&[u8]>,
+    options: &SyncOptions,
+) -> Result<SyncReport, SyncError> {
+    let mut resolver = Resolver::new(options.conflicts);
+    let diff = calc_diff_bidi(
+        &src_tree,
+        &dest_tree,
+        &load_state(src, dest),
+        &load_state(dest, src),
+        Path::new(""),
+        &mut resolver,
+    );
+    if let (ConflictPolicy::Abort, false) = (options.conflicts, resolver.conflicts.is_empty()) {
+        return Err(SyncError::Conflict(resolver.conflicts));
+    }
+    let (dest_files, dest_bytes) = diff.dest_add.totals();
+    let (src_files, src_bytes) = diff.src_add.totals();
+    options.emit(SyncEvent::Planned {
+        files: dest_files + src_files,
+        bytes: dest_bytes + src_bytes,
+    });
+    let mut to_dest = Context::new(options, src, dest);
+    let mut to_src = Context::new(options, dest, src);
+    if options.hard_links {
+        find_leaders(&src_tree, Path::new(""), &mut to_dest.leaders);
+        find_leaders(&dest_tree, Path::new(""), &mut to_src.leaders);
+    }
+    let mut report = SyncReport {
+        conflicts: resolver.conflicts,
+        ..Default::default()
+    };
+    if options.dry_run {
+        for (from, to) in diff.renames.iter() {
+            let (from, to) = (dest.join(from), dest.join(to));
+            println!("rename {} to {}", from.display(), to.display());
+        }
+        plan_remove_dir(&diff.dest_rem, Path::new(""), false, &to_dest, &mut report);
+        plan_apply_dir(
+            &diff.dest_add,
+            Path::new(""),
+            Some(&dest_tree),
+            false,
+            &to_dest,
+            &mut report,
+        );
+        plan_remove_dir(&diff.src_rem, Path::new(""), false, &to_src, &mut report);
+        plan_apply_dir(
+            &diff.src_add,
+            Path::new(""),
+            Some(&src_tree),
+            false,
+            &to_src,
+            &mut report,
+        );
+        return Ok(report);
+    }
+    for (from, to) in diff.renames {
+        let (from, to) = (dest.join(from), dest.join(to));
+        if let Err(e) = tokio::fs::rename(&from, &to).await {
+            report.fail(from, e);
+        }
+    }
+    let (to_dest, to_src) = (Arc::new(to_dest), Arc::new(to_src));
+    report.merge(remove_diff(diff.dest_rem, to_dest.clone()).await);
+    report.merge(apply_diff(diff.dest_add, to_dest).await);
+    report.merge(remove_diff(diff.src_rem, to_src.clone()).await);
+    report.merge(apply_diff(diff.src_add, to_src).await);
+    let ignores = (src_ignore, dest_ignore);
+    record_state(src, dest, ignores, options, &mut report).await;
+    Ok(report)
+}
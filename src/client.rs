@@ -5,6 +5,7 @@ use tokio::net::TcpStream;
 use crate::message::{Buffer, Message};
 use crate::Messenger;
 
+#[allow(dead_code)]
 async fn connect(address: &str, port: u16) -> Result<Messenger, ()> {
     let stream = TcpStream::connect(format!("{}:{}", address, port))
         .await
@@ -13,12 +14,13 @@ async fn connect(address: &str, port: u16) -> Result<Messenger, ()> {
     Ok(Messenger::from(stream))
 }
 
+#[allow(dead_code)]
 pub async fn handshake(address: &str, port: u16) -> Result<bool, ()> {
     let mut messenger = connect(address, port).await?;
     messenger.send(Message::Init).await?;
     let mes = messenger.recv().await?;
     match mes {
-        Buffer::Invalid | Buffer::End => return Ok(false),
+        Buffer::Invalid | Buffer::End => Ok(false),
         Buffer::Message(mes) => match mes {
             Message::Terminate => Ok(true),
             _ => Ok(false),
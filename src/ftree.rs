@@ -1,66 +1,115 @@
-use std::{path::PathBuf, sync::Arc};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    ffi::{OsStr, OsString},
+    fs::Metadata,
+    iter::Peekable,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-#[derive(Clone)]
+/// Timestamps (nanoseconds since the epoch), mode bits and ownership of a file or directory.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Attrs {
+    pub mtime: u128,
+    pub atime: u128,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FnodeFile {
     date: u128,
     size: u64,
+    hash: Option<[u8; 32]>,
+    attrs: Attrs,
+    inode: Option<(u64, u64)>,
 }
 
-#[derive(Clone, Default)]
+type Child = (OsString, Arc<Fnode>);
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct FnodeDir {
-    children: Vec<(String, Arc<Fnode>)>,
+    /// sorted by name
+    children: Vec<Child>,
     entirity: bool,
+    attrs: Option<Attrs>,
+    /// names of the entries left out of the scan by their size or age,
+    /// or because they could not be read, sorted
+    #[serde(skip)]
+    skipped: Vec<OsString>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FnodeLink {
+    target: PathBuf,
+    attrs: Attrs,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Fnode {
     File(FnodeFile),
     Dir(FnodeDir),
+    Link(FnodeLink),
 }
 
 impl FnodeDir {
-    pub fn append_dir(&mut self, name: String, fnode: FnodeDir) {
-        self.children.push((name, Arc::new(Fnode::Dir(fnode))));
+    pub fn append_dir(&mut self, name: OsString, fnode: FnodeDir) {
+        self.append(name, Arc::new(Fnode::Dir(fnode)));
+    }
+    pub fn append_file(&mut self, name: OsString, fnode: FnodeFile) {
+        self.append(name, Arc::new(Fnode::File(fnode)));
     }
-    pub fn append_file(&mut self, name: String, fnode: FnodeFile) {
-        self.children.push((name, Arc::new(Fnode::File(fnode))));
+    pub fn append_link(&mut self, name: OsString, fnode: FnodeLink) {
+        self.append(name, Arc::new(Fnode::Link(fnode)));
+    }
+    /// Add a child, replacing any with the same name. Appending in name order is cheapest.
+    pub fn append(&mut self, name: OsString, fnode: Arc<Fnode>) {
+        match self.children.last() {
+            Some((last, _)) if *last >= name => match self.search(&name) {
+                Ok(i) => self.children[i].1 = fnode,
+                Err(i) => self.children.insert(i, (name, fnode)),
+            },
+            _ => self.children.push((name, fnode)),
+        }
     }
 
-    pub fn file(&self, file: &String) -> Option<&FnodeFile> {
-        let r = self.children().iter().find(|(name, node)| {
-            if let Fnode::File(_) = node.as_ref() {
-                if name == file {
-                    return true;
-                }
-            }
-            false
-        });
-        if let Some((_, node)) = r {
-            if let Fnode::File(f) = node.as_ref() {
-                return Some(f);
-            }
+    fn search(&self, name: &OsStr) -> Result<usize, usize> {
+        self.children
+            .binary_search_by(|(n, _)| n.as_os_str().cmp(name))
+    }
+
+    pub fn get(&self, name: &OsStr) -> Option<&Arc<Fnode>> {
+        let i = self.search(name).ok()?;
+        Some(&self.children[i].1)
+    }
+
+    pub fn file(&self, file: &OsStr) -> Option<&FnodeFile> {
+        match self.get(file)?.as_ref() {
+            Fnode::File(f) => Some(f),
+            _ => None,
         }
-        None
     }
 
-    pub fn subdir(&self, dir: &String) -> Option<&FnodeDir> {
-        let r = self.children().iter().find(|(name, node)| {
-            if let Fnode::Dir(_) = node.as_ref() {
-                if name == dir {
-                    return true;
-                }
-            }
-            false
-        });
-        if let Some((_, node)) = r {
-            if let Fnode::Dir(d) = node.as_ref() {
-                return Some(d);
-            }
+    pub fn subdir(&self, dir: &OsStr) -> Option<&FnodeDir> {
+        match self.get(dir)?.as_ref() {
+            Fnode::Dir(d) => Some(d),
+            _ => None,
         }
-        None
     }
 
-    pub fn children(&self) -> &[(String, Arc<Fnode>)] {
+    /// Pair up the children of both directories by name, in one pass over both.
+    pub fn merge<'a>(&'a self, other: &'a FnodeDir) -> Merge<'a> {
+        Merge {
+            left: self.children.iter().peekable(),
+            right: other.children.iter().peekable(),
+        }
+    }
+
+    pub fn children(&self) -> &[Child] {
         self.children.as_ref()
     }
 
@@ -68,69 +117,113 @@ impl FnodeDir {
         self.entirity = entirity;
     }
 
-    pub fn set_entirity_recursively(&mut self, entirity: bool) {
-        self.set_entirity(entirity);
-        self.children = self
-            .children
-            .drain(..)
-            .map(|(n, c)| {
-                (
-                    n,
-                    match c.as_ref() {
-                        Fnode::Dir(d) => {
-                            let mut d = d.clone();
-                            d.set_entirity_recursively(entirity);
-                            Arc::new(Fnode::Dir(d))
-                        }
-                        Fnode::File(_) => c,
-                    },
-                )
+    /// Number of files and links in the tree, and total size of the files.
+    pub fn totals(&self) -> (usize, u64) {
+        self.children()
+            .iter()
+            .fold((0, 0), |(files, bytes), (_, c)| match c.as_ref() {
+                Fnode::File(f) => (files + 1, bytes + f.size()),
+                Fnode::Link(_) => (files + 1, bytes),
+                Fnode::Dir(d) => {
+                    let (f, b) = d.totals();
+                    (files + f, bytes + b)
+                }
             })
-            .collect();
     }
 
     pub fn entirity(&self) -> bool {
         self.entirity
     }
 
-    fn index(&mut self, name: &String) -> Option<usize> {
-        self.children.iter_mut().position(|(n, _)| *n == *name)
+    pub fn set_attrs(&mut self, attrs: Option<Attrs>) {
+        self.attrs = attrs;
     }
 
-    pub fn remove_path(&mut self, path: PathBuf, isdir: bool) -> Result<(), ()> {
-        let mut iter = path.iter().peekable();
-        let field = iter.next().ok_or(())?.to_str().ok_or(())?.to_string();
+    pub fn attrs(&self) -> Option<Attrs> {
+        self.attrs
+    }
 
-        if iter.peek().is_some() {
-            match self.subdir(&field) {
-                Some(dir) => {
-                    let mut dir = dir.clone();
-                    dir.remove_path(iter.collect(), isdir)?;
-                    let prev_index = self.index(&field).ok_or(())?;
-                    self.children.remove(prev_index);
-                    self.append_dir(field, dir);
-                }
-                None => return Err(()),
+    pub fn set_skipped(&mut self, skipped: Vec<OsString>) {
+        self.skipped = skipped;
+    }
+
+    /// Whether a file named `name` was left out of the scan of this directory.
+    pub fn skipped(&self, name: &OsStr) -> bool {
+        self.skipped
+            .binary_search_by(|n| n.as_os_str().cmp(name))
+            .is_ok()
+    }
+
+    /// Keep only the children for which `keep` holds, which may also change them.
+    pub fn retain(&mut self, mut keep: impl FnMut(&OsString, &mut Arc<Fnode>) -> bool) {
+        self.children.retain_mut(|(name, node)| keep(name, node));
+    }
+}
+
+/// Children of two directories with the same name, or only in one of them, in name order.
+pub struct Merge<'a> {
+    left: Peekable<std::slice::Iter<'a, Child>>,
+    right: Peekable<std::slice::Iter<'a, Child>>,
+}
+
+impl<'a> Iterator for Merge<'a> {
+    type Item = (&'a OsString, Option<&'a Arc<Fnode>>, Option<&'a Arc<Fnode>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let order = match (self.left.peek(), self.right.peek()) {
+            (Some((l, _)), Some((r, _))) => l.cmp(r),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => return None,
+        };
+        match order {
+            Ordering::Less => {
+                let (n, l) = self.left.next()?;
+                Some((n, Some(l), None))
             }
-        } else {
-            if !isdir {
-                if self.file(&field).is_some() {
-                    let prev_index = self.index(&field).ok_or(())?;
-                    self.children.remove(prev_index);
-                }
+            Ordering::Greater => {
+                let (n, r) = self.right.next()?;
+                Some((n, None, Some(r)))
             }
-            if self.subdir(&field).is_some() {
-                let prev_index = self.index(&field).ok_or(())?;
-                self.children.remove(prev_index);
+            Ordering::Equal => {
+                let (n, l) = self.left.next()?;
+                let (_, r) = self.right.next()?;
+                Some((n, Some(l), Some(r)))
             }
         }
-        Ok(())
     }
 }
 
 impl FnodeFile {
     pub fn new(date: u128, size: u64) -> FnodeFile {
-        FnodeFile { date, size }
+        FnodeFile {
+            date,
+            size,
+            hash: None,
+            attrs: Attrs::default(),
+            inode: None,
+        }
+    }
+
+    /// Record the (device, inode) pair of a file with more than one hard link.
+    pub fn set_inode(&mut self, inode: (u64, u64)) {
+        self.inode = Some(inode);
+    }
+
+    pub fn inode(&self) -> Option<(u64, u64)> {
+        self.inode
+    }
+
+    pub fn set_attrs(&mut self, attrs: Attrs) {
+        self.attrs = attrs;
+    }
+
+    pub fn attrs(&self) -> Attrs {
+        self.attrs
+    }
+
+    pub fn set_hash(&mut self, hash: [u8; 32]) {
+        self.hash = Some(hash);
     }
 
     pub fn date(&self) -> u128 {
@@ -141,4 +234,123 @@ impl FnodeFile {
     pub fn size(&self) -> u64 {
         self.size
     }
+
+    pub fn same_as(&self, other: &FnodeFile) -> bool {
+        self.size == other.size
+            && self.date == other.date
+            && match (self.hash, other.hash) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+    }
+
+    /// Whether both files hold the same content, by size and, when both have one, hash.
+    /// Unlike `same_as`, modification dates are not compared.
+    pub fn same_content(&self, other: &FnodeFile) -> bool {
+        self.size == other.size
+            && match (self.hash, other.hash) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+    }
+
+    /// Whether this file should be copied over `dest`.
+    /// Content hashes decide when both sides have one, modification dates otherwise.
+    pub fn differs_from(&self, dest: &FnodeFile) -> bool {
+        if self.size != dest.size {
+            return true;
+        }
+        match (self.hash, dest.hash) {
+            (Some(src_hash), Some(dest_hash)) => src_hash != dest_hash,
+            _ => dest.date < self.date,
+        }
+    }
+}
+
+impl FnodeLink {
+    pub fn new(target: PathBuf, attrs: Attrs) -> FnodeLink {
+        FnodeLink { target, attrs }
+    }
+
+    pub fn target(&self) -> &PathBuf {
+        &self.target
+    }
+
+    pub fn attrs(&self) -> Attrs {
+        self.attrs
+    }
+}
+
+impl Fnode {
+    /// Whether both nodes are the same kind of entry (file, directory or link).
+    pub fn same_kind(&self, other: &Fnode) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    /// Whether both nodes describe the same content, comparing directories recursively.
+    pub fn same_as(&self, other: &Fnode) -> bool {
+        match (self, other) {
+            (Fnode::File(a), Fnode::File(b)) => a.same_as(b),
+            (Fnode::Link(a), Fnode::Link(b)) => a.target == b.target,
+            (Fnode::Dir(a), Fnode::Dir(b)) => {
+                a.children.len() == b.children.len()
+                    && a.children
+                        .iter()
+                        .zip(b.children.iter())
+                        .all(|((an, ac), (bn, bc))| an == bn && ac.same_as(bc))
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether both nodes hold the same content, comparing directories recursively
+    /// but ignoring modification dates.
+    pub fn same_content(&self, other: &Fnode) -> bool {
+        match (self, other) {
+            (Fnode::File(a), Fnode::File(b)) => a.same_content(b),
+            (Fnode::Link(a), Fnode::Link(b)) => a.target == b.target,
+            (Fnode::Dir(a), Fnode::Dir(b)) => {
+                a.children.len() == b.children.len()
+                    && a.children
+                        .iter()
+                        .zip(b.children.iter())
+                        .all(|((an, ac), (bn, bc))| an == bn && ac.same_content(bc))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn mtime(&self) -> u128 {
+        match self {
+            Fnode::File(f) => f.date,
+            Fnode::Link(l) => l.attrs.mtime,
+            Fnode::Dir(d) => d.attrs.map(|a| a.mtime).unwrap_or_default(),
+        }
+    }
+
+    /// Whether this node should replace `dest`. Directories never differ by themselves.
+    pub fn differs_from(&self, dest: &Fnode) -> bool {
+        match (self, dest) {
+            (Fnode::File(src), Fnode::File(dest)) => src.differs_from(dest),
+            (Fnode::Link(src), Fnode::Link(dest)) => src.target != dest.target,
+            (Fnode::Dir(_), Fnode::Dir(_)) => false,
+            _ => true,
+        }
+    }
+}
+
+fn nanos(time: std::io::Result<SystemTime>) -> Option<u128> {
+    Some(time.ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos())
+}
+
+impl Attrs {
+    pub fn from_metadata(md: &Metadata) -> Option<Attrs> {
+        Some(Attrs {
+            mtime: nanos(md.modified())?,
+            atime: nanos(md.accessed())?,
+            mode: md.mode(),
+            uid: md.uid(),
+            gid: md.gid(),
+        })
+    }
 }
@@ -0,0 +1,334 @@
+use std::{
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    ftree::{Fnode, FnodeDir},
+    Filter,
+};
+
+/// One byte, or a wildcard, of a glob.
+#[derive(Clone)]
+enum Token {
+    Byte(u8),
+    /// `?`
+    One,
+    /// `*`
+    Any,
+    /// `[abc]`, `[a-z]` or `[!abc]`: byte ranges, and whether they are negated
+    Class(Vec<(u8, u8)>, bool),
+}
+
+/// A glob matched against one path component, or `**` for any number of them.
+#[derive(Clone)]
+enum Segment {
+    Glob(Vec<Token>),
+    AnyDepth,
+}
+
+/// One rule of an ignore file, in gitignore syntax.
+#[derive(Clone)]
+struct Pattern {
+    segments: Vec<Segment>,
+    /// `!pattern`, which brings back what an earlier rule ignored
+    negated: bool,
+    /// `pattern/`, which only matches directories
+    dir_only: bool,
+}
+
+/// Name of the files holding the ignore rules of the directory they are in.
+pub const IGNORE_FILE: &str = ".arsygnore";
+
+/// Rules deciding which paths of a tree are left out of a sync. The last rule
+/// matching a path decides, rules of a directory take precedence over those of the
+/// directories above it, and nothing below an ignored directory is considered.
+#[derive(Clone, Default)]
+pub struct Ignore {
+    /// rules of the directories above
+    parent: Option<Arc<Ignore>>,
+    /// directory the patterns are relative to
+    base: PathBuf,
+    patterns: Vec<Pattern>,
+    /// rules taking precedence over those of every directory, shared by the whole tree
+    filters: Arc<Vec<Pattern>>,
+    /// paths left out whatever the rules say, shared by the whole tree
+    excluded: Arc<Vec<Pattern>>,
+}
+
+/// Rules keeping destination paths from being removed, the last matching one deciding.
+/// Everything inside a protected directory is protected too.
+#[derive(Default)]
+pub struct Protect {
+    patterns: Vec<Pattern>,
+}
+
+/// Whether the last of `patterns` matching the path made of `names` ignores it, if any matches.
+fn decide(patterns: &[Pattern], names: &[&[u8]], is_dir: bool) -> Option<bool> {
+    let p = patterns.iter().rev().find(|p| p.matches(names, is_dir))?;
+    Some(!p.negated)
+}
+
+fn path_names(path: &Path) -> Vec<&[u8]> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.as_bytes()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Patterns of the lines of `text`, which need not be UTF-8, as names need not be.
+fn parse_lines(text: &[u8]) -> Vec<Pattern> {
+    text.split(|b| *b == b'\n')
+        .map(|l| l.strip_suffix(b"\r").unwrap_or(l))
+        .filter_map(Pattern::parse)
+        .collect()
+}
+
+fn parse_glob(glob: &[u8]) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < glob.len() {
+        let token = match glob[i] {
+            b'\\' if i + 1 < glob.len() => {
+                i += 1;
+                Token::Byte(glob[i])
+            }
+            b'?' => Token::One,
+            b'*' => Token::Any,
+            b'[' => match parse_class(&glob[i + 1..]) {
+                Some((token, len)) => {
+                    i += len;
+                    token
+                }
+                None => Token::Byte(b'['),
+            },
+            b => Token::Byte(b),
+        };
+        tokens.push(token);
+        i += 1;
+    }
+    tokens
+}
+
+/// Parse the class following a `[`, returning it with the length it spans up to its `]`.
+fn parse_class(glob: &[u8]) -> Option<(Token, usize)> {
+    let negated = matches!(glob.first(), Some(b'!') | Some(b'^'));
+    let mut i = negated as usize;
+    let mut ranges = vec![];
+    // a `]` right after the opening bracket is part of the class
+    while i < glob.len() && (glob[i] != b']' || ranges.is_empty()) {
+        let from = glob[i];
+        if i + 2 < glob.len() && glob[i + 1] == b'-' && glob[i + 2] != b']' {
+            ranges.push((from, glob[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((from, from));
+            i += 1;
+        }
+    }
+    (i < glob.len()).then_some((Token::Class(ranges, negated), i + 1))
+}
+
+fn match_glob(tokens: &[Token], name: &[u8]) -> bool {
+    match tokens.split_first() {
+        None => name.is_empty(),
+        Some((Token::Any, rest)) => (0..=name.len()).any(|i| match_glob(rest, &name[i..])),
+        Some((token, rest)) => match name.split_first() {
+            None => false,
+            Some((&b, name)) => {
+                let matched = match token {
+                    Token::Byte(t) => *t == b,
+                    Token::One => true,
+                    Token::Class(ranges, negated) => {
+                        ranges.iter().any(|&(from, to)| from <= b && b <= to) != *negated
+                    }
+                    Token::Any => unreachable!(),
+                };
+                matched && match_glob(rest, name)
+            }
+        },
+    }
+}
+
+fn match_segments(segments: &[Segment], names: &[&[u8]]) -> bool {
+    match segments.split_first() {
+        None => names.is_empty(),
+        // a trailing `**` matches everything inside, but not the directory itself
+        Some((Segment::AnyDepth, [])) => !names.is_empty(),
+        Some((Segment::AnyDepth, rest)) => {
+            (0..=names.len()).any(|i| match_segments(rest, &names[i..]))
+        }
+        Some((Segment::Glob(glob), rest)) => match names.split_first() {
+            Some((name, names)) => match_glob(glob, name) && match_segments(rest, names),
+            None => false,
+        },
+    }
+}
+
+impl Pattern {
+    fn parse(line: &[u8]) -> Option<Pattern> {
+        let mut line = line.trim_ascii_start();
+        if line.is_empty() || line[0] == b'#' {
+            return None;
+        }
+        // trailing spaces are dropped unless escaped
+        while line.ends_with(b" ") && !line.ends_with(b"\\ ") {
+            line = &line[..line.len() - 1];
+        }
+        let negated = line[0] == b'!';
+        if negated {
+            line = &line[1..];
+        }
+        let dir_only = line.ends_with(b"/");
+        let line = line.strip_suffix(b"/").unwrap_or(line);
+        // a pattern with a slash is relative to the root, any other matches at any depth
+        let anchored = line.contains(&b'/');
+        let line = line.strip_prefix(b"/").unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+        let mut segments = vec![];
+        if !anchored {
+            segments.push(Segment::AnyDepth);
+        }
+        for part in line.split(|b| *b == b'/').filter(|p| !p.is_empty()) {
+            segments.push(match part {
+                b"**" => Segment::AnyDepth,
+                glob => Segment::Glob(parse_glob(glob)),
+            });
+        }
+        Some(Pattern {
+            segments,
+            negated,
+            dir_only,
+        })
+    }
+
+    /// A pattern matching exactly `path`, relative to the root.
+    fn literal(path: &Path, dir_only: bool) -> Pattern {
+        let segments = path
+            .iter()
+            .map(|name| Segment::Glob(name.as_bytes().iter().map(|b| Token::Byte(*b)).collect()))
+            .collect();
+        Pattern {
+            segments,
+            negated: false,
+            dir_only,
+        }
+    }
+
+    fn matches(&self, names: &[&[u8]], is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && match_segments(&self.segments, names)
+    }
+}
+
+impl Ignore {
+    /// Compile the rules of an ignore file at the root, one per line.
+    pub fn parse(text: &[u8]) -> Ignore {
+        Ignore {
+            patterns: parse_lines(text),
+            ..Default::default()
+        }
+    }
+
+    /// Add `filters` to the rules taking precedence over those of every directory.
+    pub fn filter(&mut self, filters: &[Filter]) {
+        let patterns = filters.iter().filter_map(|f| match f {
+            Filter::Exclude(line) => Pattern::parse(line.as_bytes()),
+            Filter::Include(line) => Pattern::parse(line.as_bytes()).map(|p| Pattern {
+                negated: !p.negated,
+                ..p
+            }),
+            Filter::Protect(_) => None,
+        });
+        Arc::make_mut(&mut self.filters).extend(patterns);
+    }
+
+    /// Ignore exactly `path`, relative to the root, whatever the other rules say.
+    pub fn exclude(&mut self, path: &Path, dir_only: bool) {
+        Arc::make_mut(&mut self.excluded).push(Pattern::literal(path, dir_only));
+    }
+
+    /// These rules, followed by those of the ignore file `text` of the directory at `rel`.
+    pub fn nested(self: &Arc<Ignore>, rel: &Path, text: &[u8]) -> Arc<Ignore> {
+        Arc::new(Ignore {
+            parent: Some(self.clone()),
+            base: rel.to_path_buf(),
+            patterns: parse_lines(text),
+            filters: self.filters.clone(),
+            excluded: self.excluded.clone(),
+        })
+    }
+
+    /// Whether the entry at `rel`, relative to the root, is ignored by its own path.
+    /// This does not consider whether a directory above it is ignored.
+    pub fn is_ignored(&self, rel: &Path, is_dir: bool) -> bool {
+        let names = path_names(rel);
+        if self.excluded.iter().any(|p| p.matches(&names, is_dir)) {
+            return true;
+        }
+        if let Some(p) = self
+            .filters
+            .iter()
+            .rev()
+            .find(|p| p.matches(&names, is_dir))
+        {
+            return !p.negated;
+        }
+        let mut rules = Some(self);
+        while let Some(r) = rules {
+            if let Ok(inner) = rel.strip_prefix(&r.base) {
+                let inner = path_names(inner);
+                if let Some(p) = r.patterns.iter().rev().find(|p| p.matches(&inner, is_dir)) {
+                    return !p.negated;
+                }
+            }
+            rules = r.parent.as_deref();
+        }
+        false
+    }
+
+    /// Remove every ignored entry of `dir`, whose path relative to the root is `rel`.
+    pub fn prune(&self, dir: &mut FnodeDir, rel: &Path) {
+        dir.retain(|name, node| {
+            let path = rel.join(name);
+            let is_dir = matches!(node.as_ref(), Fnode::Dir(_));
+            if self.is_ignored(&path, is_dir) {
+                return false;
+            }
+            if is_dir {
+                if let Fnode::Dir(sub) = Arc::make_mut(node) {
+                    self.prune(sub, &path);
+                }
+            }
+            true
+        });
+    }
+}
+
+impl Protect {
+    /// Compile the protect rules among `filters`.
+    pub fn new(filters: &[Filter]) -> Protect {
+        let patterns = filters
+            .iter()
+            .filter_map(|f| match f {
+                Filter::Protect(line) => Pattern::parse(line.as_bytes()),
+                _ => None,
+            })
+            .collect();
+        Protect { patterns }
+    }
+
+    /// Whether the destination entry at `rel` must not be removed,
+    /// because of its own path or that of a directory above it.
+    pub fn protects(&self, rel: &Path, is_dir: bool) -> bool {
+        let names = path_names(rel);
+        (1..=names.len()).any(|i| {
+            let is_dir = is_dir || i < names.len();
+            decide(&self.patterns, &names[..i], is_dir).unwrap_or(false)
+        })
+    }
+}
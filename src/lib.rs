@@ -1,86 +1,552 @@
+mod bidi;
 mod client;
 mod daemon;
 mod ftree;
+mod ignore;
 mod message;
+mod prune;
+mod report;
+mod stream;
 
 pub use message::Messenger;
+pub use prune::{prune_snapshots, Retention};
+pub use report::{Conflict, Resolution, SyncError, SyncEvent, SyncReport};
 
-use ftree::{Fnode, FnodeDir, FnodeFile};
-use futures::{future::BoxFuture, FutureExt};
+use filetime::FileTime;
+use ftree::{Attrs, Fnode, FnodeDir, FnodeFile, FnodeLink};
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use ignore::{Ignore, Protect, IGNORE_FILE};
 
 pub use daemon::run_daemon;
-use std::{fs::read_dir, path::PathBuf, sync::Arc, time::SystemTime};
+use std::{
+    collections::{BTreeSet, HashMap},
+    ffi::OsString,
+    fs::{read_dir, Metadata},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc::UnboundedSender, Semaphore, SemaphorePermit},
+};
 
+#[derive(Clone, Copy, Default)]
 pub enum SyncMode {
+    #[default]
     Mixed,
     Soft,
     Hard,
     Update,
+    /// propagate changes made on either side to the other one
+    Bidirectional,
 }
 
-fn traverse_dir(dir: &PathBuf) -> Option<FnodeDir> {
-    let mut tree = ftree::FnodeDir::default();
-    for entry in read_dir(dir).ok()?.filter_map(|e| e.ok()) {
-        (|| {
-            let path = entry.path();
-            let kind = entry.file_type().ok()?;
-            if kind.is_dir() {
-                if let Some(dir) = traverse_dir(&path) {
-                    tree.append_dir(entry.file_name().to_str()?.to_string(), dir);
+#[derive(Clone, Default)]
+pub struct SyncOptions {
+    pub mode: SyncMode,
+    pub verbose: bool,
+    /// compute the diff and print what would be done without touching the destination
+    pub dry_run: bool,
+    /// compare files of equal size by content hash instead of modification date
+    pub checksum: bool,
+    /// give written files and directories the times, mode bits and (when permitted) owner of the source
+    pub archive: bool,
+    /// how symbolic links found in the source are synced
+    pub links: LinkPolicy,
+    /// recreate files hard linked together in the source as hard links in the destination,
+    /// and the other way round in bidirectional syncs
+    pub hard_links: bool,
+    /// flush every copied file to disk before renaming it into place
+    pub fsync: bool,
+    /// directory for files being written, relative to the directory they are written to
+    /// unless absolute. Files are written next to their destination by default.
+    /// Files are renamed out of it into place, so it must be on the same file system
+    /// as the directories written to; a sync with an absolute one that is not fails.
+    pub partial_dir: Option<PathBuf>,
+    /// how many file operations may run at once, `DEFAULT_JOBS` if zero
+    pub jobs: usize,
+    /// how paths changed on both sides of a bidirectional sync are resolved
+    pub conflicts: ConflictPolicy,
+    /// directory, relative to the destination unless absolute, that destination files are
+    /// moved to before being removed or replaced. It is never synced itself.
+    pub backup_dir: Option<PathBuf>,
+    /// appended to the names of backed up files
+    pub backup_suffix: String,
+    /// previous snapshot, relative to the destination unless absolute, that files unchanged
+    /// since are hard linked from instead of being copied
+    pub link_dest: Option<PathBuf>,
+    /// where to send progress events, if anywhere
+    pub events: Option<UnboundedSender<SyncEvent>>,
+    /// diff and sync one directory at a time rather than scanning both trees first,
+    /// which bounds memory use. Hard links are not detected this way, and
+    /// bidirectional syncs always scan both trees.
+    pub streaming: bool,
+    /// rules applied to both trees, the last matching one deciding,
+    /// which take precedence over those of their ignore files
+    pub filters: Vec<Filter>,
+    /// which source files are synced by size and age, except in bidirectional syncs
+    pub limits: Limits,
+}
+
+#[derive(Clone, Copy, Default)]
+pub enum ConflictPolicy {
+    /// keep the most recently modified version
+    #[default]
+    Newest,
+    /// keep the source version
+    Source,
+    /// keep both, renaming the destination version to `<name>.conflict-<host>-<time>`
+    KeepBoth,
+    /// leave both replicas untouched
+    Abort,
+}
+
+/// A rule in the syntax of ignore files, given along with the trees rather than in them.
+#[derive(Clone)]
+pub enum Filter {
+    /// leave the paths matched out of the sync
+    Exclude(OsString),
+    /// sync the paths matched even if an earlier filter or an ignore file excludes them
+    Include(OsString),
+    /// never remove the destination paths matched, though they may still be updated
+    Protect(OsString),
+}
+
+/// Bounds on the size and modification time of the source files synced. Files outside
+/// them are left out of the sync, without their destination counterparts being removed.
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// only files modified after this are synced
+    pub newer_than: Option<SystemTime>,
+    /// only files modified before this are synced
+    pub older_than: Option<SystemTime>,
+}
+
+impl Limits {
+    fn admit(&self, md: &Metadata) -> bool {
+        let (size, modified) = (md.len(), md.modified().ok());
+        self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max)
+            && self
+                .newer_than
+                .is_none_or(|t| modified.is_some_and(|m| m > t))
+            && self
+                .older_than
+                .is_none_or(|t| modified.is_some_and(|m| m < t))
+    }
+}
+
+pub const DEFAULT_JOBS: usize = 16;
+
+impl SyncOptions {
+    fn jobs(&self) -> usize {
+        match self.jobs {
+            0 => DEFAULT_JOBS,
+            jobs => jobs,
+        }
+    }
+
+    fn emit(&self, event: SyncEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub enum LinkPolicy {
+    /// leave symbolic links out of the sync
+    #[default]
+    Skip,
+    /// recreate symbolic links as links with the same target
+    Preserve,
+    /// sync whatever the links point to as regular files and directories
+    Follow,
+    /// preserve only the links with a relative target that resolves to a path inside
+    /// the source tree
+    Internal,
+}
+
+fn hash_file(path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(std::fs::File::open(path)?)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// The attributes of `md`, which cannot be recorded for times before the epoch.
+fn read_attrs(md: &Metadata) -> std::io::Result<Attrs> {
+    Attrs::from_metadata(md).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "time before the epoch")
+    })
+}
+
+fn read_link(path: &Path, md: &Metadata) -> std::io::Result<FnodeLink> {
+    let target = std::fs::read_link(path)?;
+    Ok(FnodeLink::new(target, read_attrs(md)?))
+}
+
+fn read_file(path: &Path, md: &Metadata, options: &SyncOptions) -> std::io::Result<FnodeFile> {
+    let attrs = read_attrs(md)?;
+    let mut file = FnodeFile::new(attrs.mtime, md.len());
+    file.set_attrs(attrs);
+    if md.nlink() > 1 {
+        file.set_inode((md.dev(), md.ino()));
+    }
+    if options.checksum {
+        file.set_hash(hash_file(path)?);
+    }
+    Ok(file)
+}
+
+/// An entry read from a directory: a file or link, or a subdirectory still to be scanned.
+enum Scanned {
+    Node(OsString, Fnode),
+    Dir(OsString, PathBuf),
+}
+
+impl Scanned {
+    fn name(&self) -> &OsString {
+        match self {
+            Scanned::Node(name, _) | Scanned::Dir(name, _) => name,
+        }
+    }
+}
+
+/// What reading one directory yields.
+struct Listing {
+    attrs: Option<Attrs>,
+    /// (device, inode) pair of the directory
+    id: (u64, u64),
+    entries: Vec<Scanned>,
+    /// names of the entries left out by the limits of the scan or because they could
+    /// not be read, sorted
+    skipped: Vec<OsString>,
+    /// entries that could not be read
+    failures: Vec<(PathBuf, std::io::Error)>,
+    /// rules for the entries of the directory, including those of its own ignore file
+    ignore: Arc<Ignore>,
+}
+
+/// State shared by every directory of one traversal.
+struct Scan {
+    /// canonical path of the tree being scanned
+    root: PathBuf,
+    links: LinkPolicy,
+    limits: Limits,
+    options: SyncOptions,
+    /// one permit per directory allowed to be read at once
+    permits: Semaphore,
+    /// whether the tree is scanned to be removed, so that ignore files are not read
+    /// and special files are kept
+    removal: bool,
+}
+
+/// Read the entries of `dir`, found at `rel` in the tree, handling symbolic links according
+/// to the policy of `scan`, along with its attributes. The entries that `ignore`, or the
+/// ignore file of `dir` unless the scan is a removal, exclude are left out without being
+/// looked at. `ancestors` are the (device, inode) pairs of the directories above `dir`,
+/// which stop followed links from looping. This blocks.
+fn read_entries(
+    dir: &Path,
+    rel: &Path,
+    scan: &Scan,
+    ancestors: &[(u64, u64)],
+    ignore: &Arc<Ignore>,
+) -> std::io::Result<Listing> {
+    let md = std::fs::metadata(dir)?;
+    let id = (md.dev(), md.ino());
+    // a listing cut short would make the missing entries look deleted
+    let listed = read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    // rules that cannot be read would let through what they exclude
+    let text = if !scan.removal && listed.iter().any(|e| e.file_name() == IGNORE_FILE) {
+        Some(std::fs::read(dir.join(IGNORE_FILE))?)
+    } else {
+        None
+    };
+    let ignore = match text {
+        Some(text) => ignore.nested(rel, &text),
+        None => ignore.clone(),
+    };
+    let mut entries = vec![];
+    let mut skipped = vec![];
+    let mut failures = vec![];
+    for entry in listed {
+        let path = entry.path();
+        let name = entry.file_name();
+        let read = (|| {
+            let file_type = entry.file_type()?;
+            let is_dir = match (file_type.is_symlink(), scan.links) {
+                (true, LinkPolicy::Follow) => std::fs::metadata(&path)?.is_dir(),
+                _ => file_type.is_dir(),
+            };
+            if ignore.is_ignored(&rel.join(&name), is_dir) {
+                return Ok(None);
+            }
+            let mut md = entry.metadata()?;
+            if md.file_type().is_symlink() {
+                match scan.links {
+                    LinkPolicy::Skip => return Ok(None),
+                    LinkPolicy::Preserve => {
+                        let link = read_link(&path, &md)?;
+                        return Ok(Some(Scanned::Node(name.clone(), Fnode::Link(link))));
+                    }
+                    LinkPolicy::Internal => {
+                        // a link that does not resolve does not point inside the tree
+                        let inside = std::fs::canonicalize(&path)
+                            .map(|target| target.starts_with(&scan.root))
+                            .unwrap_or(false);
+                        if !inside {
+                            return Ok(None);
+                        }
+                        // an absolute target would still point into the source tree
+                        // once copied, so only relative links are kept
+                        let link = read_link(&path, &md)?;
+                        if link.target().is_absolute() {
+                            return Ok(None);
+                        }
+                        return Ok(Some(Scanned::Node(name.clone(), Fnode::Link(link))));
+                    }
+                    LinkPolicy::Follow => md = std::fs::metadata(&path)?,
+                }
+            }
+            if md.is_dir() {
+                let sub = (md.dev(), md.ino());
+                if sub == id || ancestors.contains(&sub) {
+                    return Ok(None);
+                }
+                Ok(Some(Scanned::Dir(name.clone(), path.clone())))
+            } else if md.is_file() || scan.removal {
+                // fifos, sockets and devices are only looked at to be removed, as files
+                if !scan.limits.admit(&md) {
+                    skipped.push(name.clone());
+                    return Ok(None);
                 }
-            } else if kind.is_file() {
-                let md = entry.metadata().ok()?;
-                let time = md.modified().ok()?;
-                let dur = time.duration_since(SystemTime::UNIX_EPOCH).ok()?;
-                let file = FnodeFile::new(dur.as_nanos(), md.len());
-                tree.append_file(entry.file_name().to_str()?.to_string(), file);
+                let file = read_file(&path, &md, &scan.options)?;
+                Ok(Some(Scanned::Node(name.clone(), Fnode::File(file))))
+            } else {
+                Ok(None)
             }
-            Some(())
         })();
+        match read {
+            Ok(Some(scanned)) => entries.push(scanned),
+            Ok(None) => {}
+            Err(e) => {
+                skipped.push(name);
+                failures.push((path, e));
+            }
+        }
+    }
+    // trees are cheapest to build in name order
+    entries.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+    skipped.sort_unstable();
+    Ok(Listing {
+        attrs: Attrs::from_metadata(&md),
+        id,
+        entries,
+        skipped,
+        failures,
+        ignore,
+    })
+}
+
+impl Scan {
+    async fn new(
+        dir: &Path,
+        links: LinkPolicy,
+        limits: Limits,
+        options: &SyncOptions,
+    ) -> Option<Scan> {
+        Some(Scan {
+            root: tokio::fs::canonicalize(dir).await.ok()?,
+            links,
+            limits,
+            options: options.clone(),
+            permits: Semaphore::new(options.jobs()),
+            removal: false,
+        })
+    }
+
+    fn fail(&self, report: &mut SyncReport, path: PathBuf, error: std::io::Error) {
+        self.options.emit(SyncEvent::Failed {
+            path: path.clone(),
+            error: error.to_string(),
+        });
+        report.fail(path, error);
+    }
+}
+
+/// `read_entries` on the blocking thread pool, at most `jobs` directories at once.
+async fn read_listing(
+    dir: PathBuf,
+    rel: PathBuf,
+    ancestors: Arc<Vec<(u64, u64)>>,
+    ignore: Arc<Ignore>,
+    scan: Arc<Scan>,
+) -> std::io::Result<Listing> {
+    let _permit = scan
+        .permits
+        .acquire()
+        .await
+        .map_err(std::io::Error::other)?;
+    let reader = scan.clone();
+    tokio::task::spawn_blocking(move || read_entries(&dir, &rel, &reader, &ancestors, &ignore))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// Scan `dir`, found at `rel` in the tree, into a tree, scanning sibling
+/// directories concurrently and leaving out what `ignore` excludes.
+/// Entries that cannot be read are reported, and recorded as skipped so that
+/// what is in their place in the destination is left alone.
+fn scan_dir(
+    dir: PathBuf,
+    rel: PathBuf,
+    ancestors: Arc<Vec<(u64, u64)>>,
+    ignore: Arc<Ignore>,
+    scan: Arc<Scan>,
+) -> BoxFuture<'static, std::io::Result<(FnodeDir, SyncReport)>> {
+    async move {
+        let read = read_listing(dir, rel.clone(), ancestors.clone(), ignore, scan.clone()).await;
+        let Listing {
+            attrs,
+            id,
+            entries,
+            mut skipped,
+            failures,
+            ignore,
+        } = read?;
+        let mut report = SyncReport::default();
+        for (path, e) in failures {
+            scan.fail(&mut report, path, e);
+        }
+        let mut ancestors = ancestors.as_ref().clone();
+        ancestors.push(id);
+        let ancestors = Arc::new(ancestors);
+        let subdirs: Vec<_> = entries
+            .iter()
+            .filter_map(|e| match e {
+                Scanned::Dir(name, path) => Some(scan_dir(
+                    path.clone(),
+                    rel.join(name),
+                    ancestors.clone(),
+                    ignore.clone(),
+                    scan.clone(),
+                )),
+                Scanned::Node(..) => None,
+            })
+            .collect();
+        let mut subdirs = futures::future::join_all(subdirs).await.into_iter();
+        let mut tree = FnodeDir::default();
+        tree.set_attrs(attrs);
+        for entry in entries {
+            match entry {
+                Scanned::Node(name, node) => tree.append(name, Arc::new(node)),
+                Scanned::Dir(name, path) => match subdirs.next() {
+                    Some(Ok((dir, scanned))) => {
+                        tree.append_dir(name, dir);
+                        report.merge(scanned);
+                    }
+                    Some(Err(e)) => {
+                        scan.fail(&mut report, path, e);
+                        skipped.push(name);
+                    }
+                    None => {}
+                },
+            }
+        }
+        skipped.sort_unstable();
+        tree.set_skipped(skipped);
+        Ok((tree, report))
+    }
+    .boxed()
+}
+
+/// Scan the tree at `dir`, leaving out what `ignore` and its ignore files exclude
+/// and the files outside `limits`. Also returns the failures to read parts of it.
+async fn traverse_dir(
+    dir: &Path,
+    links: LinkPolicy,
+    limits: Limits,
+    ignore: Arc<Ignore>,
+    options: &SyncOptions,
+) -> Option<(FnodeDir, SyncReport)> {
+    options.emit(SyncEvent::ScanStarted(dir.to_path_buf()));
+    let scan = Arc::new(Scan::new(dir, links, limits, options).await?);
+    let (root, rel) = (dir.to_path_buf(), PathBuf::new());
+    let (tree, report) = scan_dir(root, rel, Arc::new(vec![]), ignore, scan)
+        .await
+        .ok()?;
+    let (files, bytes) = tree.totals();
+    options.emit(SyncEvent::ScanFinished {
+        root: dir.to_path_buf(),
+        files,
+        bytes,
+    });
+    Some((tree, report))
+}
+
+/// Scan the tree at `dir` to remove it as a whole. Links are not followed, ignore files
+/// are not read and special files are recorded as files, so that nothing in it is left out.
+async fn scan_removal(dir: &Path) -> Option<(FnodeDir, SyncReport)> {
+    let options = SyncOptions::default();
+    let scan = Scan::new(dir, LinkPolicy::Preserve, Limits::default(), &options).await?;
+    let scan = Arc::new(Scan {
+        removal: true,
+        ..scan
+    });
+    let (root, rel) = (dir.to_path_buf(), PathBuf::new());
+    scan_dir(root, rel, Arc::new(vec![]), Arc::default(), scan)
+        .await
+        .ok()
+}
+
+/// `node` marked to be created or removed as a whole,
+/// which implies the same for every directory below it.
+fn entire(node: &Arc<Fnode>) -> Arc<Fnode> {
+    match node.as_ref() {
+        Fnode::Dir(d) if !d.entirity() => {
+            let mut d = d.clone();
+            d.set_entirity(true);
+            Arc::new(Fnode::Dir(d))
+        }
+        _ => node.clone(),
     }
-    Some(tree)
 }
 
 fn calc_diff_hard(src: &FnodeDir, dest: &FnodeDir) -> (FnodeDir, FnodeDir) {
     let mut diff_add = FnodeDir::default();
+    diff_add.set_attrs(src.attrs());
     let mut diff_rem = FnodeDir::default();
 
-    for (n, f) in dest.children().iter() {
-        match f.as_ref() {
-            Fnode::Dir(dest_sub) => match src.subdir(n) {
-                Some(src_sub) => {
+    for (n, s, d) in src.merge(dest) {
+        match (s, d) {
+            // entries left out of the source are not removed
+            (None, Some(_)) if src.skipped(n) => {}
+            (Some(s), Some(d)) if s.same_kind(d) => match (s.as_ref(), d.as_ref()) {
+                (Fnode::Dir(src_sub), Fnode::Dir(dest_sub)) => {
                     let (sub_add, sub_rem) = calc_diff_hard(src_sub, dest_sub);
                     diff_add.append_dir(n.clone(), sub_add);
                     diff_rem.append_dir(n.clone(), sub_rem);
                 }
-                None => {
-                    let mut dest_sub = dest_sub.clone();
-                    dest_sub.set_entirity_recursively(true);
-                    diff_rem.append_dir(n.clone(), dest_sub);
-                }
-            },
-            Fnode::File(dest_file) => match src.file(n) {
-                Some(src_file) => {
-                    if dest_file.size() != src_file.size() || dest_file.date() < src_file.date() {
-                        diff_add.append_file(n.clone(), src_file.clone());
+                _ => {
+                    if s.differs_from(d) {
+                        diff_add.append(n.clone(), s.clone());
                     }
                 }
-                None => diff_rem.append_file(n.clone(), dest_file.clone()),
             },
-        }
-    }
-    for (n, f) in src.children().iter() {
-        match f.as_ref() {
-            Fnode::Dir(src_sub) => {
-                if dest.subdir(n).is_none() {
-                    let mut src_sub = src_sub.clone();
-                    src_sub.set_entirity_recursively(true);
-                    diff_add.append_dir(n.clone(), src_sub);
+            _ => {
+                if let Some(d) = d {
+                    diff_rem.append(n.clone(), entire(d));
                 }
-            }
-            Fnode::File(src_file) => {
-                if dest.file(n).is_none() {
-                    diff_add.append_file(n.clone(), src_file.clone())
+                if let Some(s) = s {
+                    diff_add.append(n.clone(), entire(s));
                 }
             }
         }
@@ -90,19 +556,18 @@ fn calc_diff_hard(src: &FnodeDir, dest: &FnodeDir) -> (FnodeDir, FnodeDir) {
 
 fn calc_diff_update(src: &FnodeDir, dest: &FnodeDir) -> FnodeDir {
     let mut diff_add = FnodeDir::default();
+    diff_add.set_attrs(src.attrs());
 
-    for (n, f) in dest.children().iter() {
-        match f.as_ref() {
-            Fnode::Dir(dest_sub) => {
-                if let Some(src_sub) = src.subdir(n) {
+    for (n, s, d) in src.merge(dest) {
+        if let (Some(s), Some(d)) = (s, d) {
+            match (s.as_ref(), d.as_ref()) {
+                (Fnode::Dir(src_sub), Fnode::Dir(dest_sub)) => {
                     let sub_add = calc_diff_update(src_sub, dest_sub);
                     diff_add.append_dir(n.clone(), sub_add);
                 }
-            }
-            Fnode::File(dest_file) => {
-                if let Some(src_file) = src.file(n) {
-                    if dest_file.size() != src_file.size() || dest_file.date() < src_file.date() {
-                        diff_add.append_file(n.clone(), src_file.clone());
+                _ => {
+                    if s.same_kind(d) && s.differs_from(d) {
+                        diff_add.append(n.clone(), s.clone());
                     }
                 }
             }
@@ -113,163 +578,921 @@ fn calc_diff_update(src: &FnodeDir, dest: &FnodeDir) -> FnodeDir {
 
 fn calc_diff_soft(src: &FnodeDir, dest: &FnodeDir, mixed: bool) -> (FnodeDir, FnodeDir) {
     let mut diff_add = FnodeDir::default();
+    diff_add.set_attrs(src.attrs());
     let mut diff_rem = FnodeDir::default();
-    for (n, f) in src.children().iter() {
-        match f.as_ref() {
-            Fnode::Dir(dir) => match dest.subdir(n) {
-                Some(sub) => {
-                    let (sub_add, sub_rem) = calc_diff_soft(&dir, sub, mixed);
+    for (n, s, d) in src.merge(dest) {
+        let s = match s {
+            Some(s) => s,
+            None => continue,
+        };
+        match d {
+            None => diff_add.append(n.clone(), entire(s)),
+            Some(d) if d.same_kind(s) => match (s.as_ref(), d.as_ref()) {
+                (Fnode::Dir(dir), Fnode::Dir(sub)) => {
+                    let (sub_add, sub_rem) = calc_diff_soft(dir, sub, mixed);
                     diff_add.append_dir(n.clone(), sub_add);
                     diff_rem.append_dir(n.clone(), sub_rem);
                 }
-                None => {
-                    let mut add_flag = false;
-                    if let Some(f) = dest.file(n) {
-                        if mixed {
-                            diff_rem.append_file(n.clone(), f.clone());
-                            add_flag = true;
-                        }
-                    } else {
-                        add_flag = true;
-                    }
-                    if add_flag {
-                        let mut dir = dir.clone();
-                        dir.set_entirity_recursively(true);
-                        diff_add.append_dir(n.clone(), dir)
+                _ => {
+                    if s.differs_from(d) {
+                        diff_add.append(n.clone(), s.clone());
                     }
                 }
             },
-            Fnode::File(file) => match dest.file(n) {
-                Some(f) => {
-                    if f.size() != file.size() || f.date() < file.date() {
-                        diff_add.append_file(n.clone(), file.clone());
-                    }
-                }
-                None => {
-                    if let Some(d) = dest.subdir(n) {
-                        if mixed {
-                            let mut d = d.clone();
-                            d.set_entirity(true);
-                            diff_rem.append_dir(n.clone(), d);
-                            diff_add.append_file(n.clone(), file.clone())
-                        }
-                    } else {
-                        diff_add.append_file(n.clone(), file.clone())
-                    }
+            Some(d) => {
+                if mixed {
+                    diff_rem.append(n.clone(), entire(d));
+                    diff_add.append(n.clone(), entire(s));
                 }
-            },
+            }
         }
     }
     (diff_add, diff_rem)
 }
 
-fn remove_diff_node(node: Arc<Fnode>, dest: PathBuf, verbose: bool) -> BoxFuture<'static, ()> {
+fn file_time(nanos: u128) -> FileTime {
+    FileTime::from_unix_time(
+        (nanos / 1_000_000_000) as i64,
+        (nanos % 1_000_000_000) as u32,
+    )
+}
+
+fn permitted(result: std::io::Result<()>) -> std::io::Result<()> {
+    match result {
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Ok(()),
+        result => result,
+    }
+}
+
+/// Give `path` the attributes recorded from its source. Ownership is only changed
+/// where permitted, so running unprivileged still preserves times and mode bits.
+fn set_attrs(path: &Path, attrs: Attrs) -> std::io::Result<()> {
+    permitted(std::os::unix::fs::chown(
+        path,
+        Some(attrs.uid),
+        Some(attrs.gid),
+    ))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(attrs.mode & 0o7777))?;
+    filetime::set_file_times(path, file_time(attrs.atime), file_time(attrs.mtime))
+}
+
+/// Same as `set_attrs` for the link itself rather than its target. Links have no mode bits.
+fn set_link_attrs(path: &Path, attrs: Attrs) -> std::io::Result<()> {
+    permitted(std::os::unix::fs::lchown(
+        path,
+        Some(attrs.uid),
+        Some(attrs.gid),
+    ))?;
+    filetime::set_symlink_file_times(path, file_time(attrs.atime), file_time(attrs.mtime))
+}
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The partial directory for files written to `dir`, if one is set.
+/// It is relative to `dir` unless absolute.
+fn partial_dir(dir: &Path, options: &SyncOptions) -> Option<PathBuf> {
+    Some(dir.join(options.partial_dir.as_ref()?))
+}
+
+/// Check that files written in an absolute partial directory can be renamed into
+/// `root`, which needs both on the same file system. A missing partial directory
+/// will be created in its closest existing ancestor.
+fn check_partial_dir(root: &Path, options: &SyncOptions) -> Result<(), SyncError> {
+    let partial = match &options.partial_dir {
+        Some(partial) if partial.is_absolute() => partial,
+        _ => return Ok(()),
+    };
+    let dev = |path: &Path| std::fs::metadata(path).map(|md| md.dev()).ok();
+    let partial_dev = partial.ancestors().find_map(dev);
+    match (dev(root), partial_dev) {
+        (Some(root), Some(partial)) if root != partial => Err(SyncError::PartialDir),
+        _ => Ok(()),
+    }
+}
+
+/// Create the partial directory for files written to `dir`, if one is set.
+async fn make_partial_dir(dir: &Path, options: &SyncOptions) -> std::io::Result<()> {
+    match partial_dir(dir, options) {
+        Some(partial) => tokio::fs::create_dir_all(partial).await,
+        None => Ok(()),
+    }
+}
+
+/// Remove the partial directory for files written to `dir` if it is relative to it,
+/// and so one of many, and is left empty.
+async fn remove_partial_dir(dir: &Path, options: &SyncOptions) {
+    if let Some(partial) = &options.partial_dir {
+        if partial.is_relative() {
+            let _ = tokio::fs::remove_dir(dir.join(partial)).await;
+        }
+    }
+}
+
+/// A unique hidden path to write `dest` to before it is renamed into place.
+/// It lives next to `dest`, or in the partial directory if one is set,
+/// which must have been made with `make_partial_dir`.
+fn temp_path(dest: &Path, options: &SyncOptions) -> PathBuf {
+    let parent = dest.parent().unwrap_or(Path::new(""));
+    let dir = partial_dir(parent, options).unwrap_or_else(|| parent.to_path_buf());
+    let mut name = OsString::from(".");
+    name.push(dest.file_name().unwrap_or_default());
+    name.push(format!(
+        ".{}.arsync",
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    dir.join(name)
+}
+
+/// Rename `tmp` over `dest` once it is `written`, removing it if anything failed.
+async fn commit<T>(written: std::io::Result<T>, tmp: &Path, dest: &Path) -> std::io::Result<T> {
+    let result = match written {
+        Ok(value) => tokio::fs::rename(tmp, dest).await.map(|_| value),
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(tmp).await;
+    }
+    result
+}
+
+async fn make_link(link: &FnodeLink, dest: &Path, options: &SyncOptions) -> std::io::Result<()> {
+    let tmp = temp_path(dest, options);
+    let written = async {
+        tokio::fs::symlink(link.target(), &tmp).await?;
+        if options.archive {
+            set_link_attrs(&tmp, link.attrs())?;
+        }
+        Ok(())
+    }
+    .await;
+    commit(written, &tmp, dest).await
+}
+
+async fn link_atomic(target: &Path, dest: &Path, options: &SyncOptions) -> std::io::Result<()> {
+    let tmp = temp_path(dest, options);
+    commit(tokio::fs::hard_link(target, &tmp).await, &tmp, dest).await
+}
+
+/// Same as `tokio::fs::copy`, sending the progress of writing `dest` through `tmp`.
+async fn copy_reporting(
+    src: &Path,
+    tmp: &Path,
+    dest: &Path,
+    options: &SyncOptions,
+) -> std::io::Result<u64> {
+    let mut reader = tokio::fs::File::open(src).await?;
+    let permissions = reader.metadata().await?.permissions();
+    // never readable by more than the source, even before the copy is complete
+    let mut writer = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(permissions.mode())
+        .open(tmp)
+        .await?;
+    let mut buf = vec![0; 1 << 17];
+    let mut bytes = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buf[..n]).await?;
+        bytes += n as u64;
+        options.emit(SyncEvent::FileProgress {
+            path: dest.to_path_buf(),
+            bytes,
+        });
+    }
+    writer.flush().await?;
+    writer.set_permissions(permissions).await?;
+    Ok(bytes)
+}
+
+async fn copy_atomic(
+    f: &FnodeFile,
+    src: &Path,
+    dest: &Path,
+    options: &SyncOptions,
+) -> std::io::Result<u64> {
+    let tmp = temp_path(dest, options);
+    let written = async {
+        let bytes = match options.events {
+            Some(_) => copy_reporting(src, &tmp, dest, options).await?,
+            None => tokio::fs::copy(src, &tmp).await?,
+        };
+        if options.fsync {
+            tokio::fs::File::open(&tmp).await?.sync_all().await?;
+        }
+        if options.archive {
+            set_attrs(&tmp, f.attrs())?;
+        }
+        Ok(bytes)
+    }
+    .await;
+    commit(written, &tmp, dest).await
+}
+
+/// State shared by every node of one remove or apply pass.
+struct Context {
+    options: SyncOptions,
+    src: PathBuf,
+    dest: PathBuf,
+    /// relative source path of the first file found for every hard-linked inode
+    leaders: HashMap<(u64, u64), PathBuf>,
+    /// one permit per file operation allowed to run at once
+    permits: Semaphore,
+    protect: Protect,
+}
+
+/// `path`, if it is a file the same as `file`, which can be hard linked instead of copying it.
+fn same_reference(file: &FnodeFile, path: PathBuf, options: &SyncOptions) -> Option<PathBuf> {
+    let md = std::fs::symlink_metadata(&path).ok()?;
+    if !md.is_file() {
+        return None;
+    }
+    let reference = read_file(&path, &md, options).ok()?;
+    let (a, b) = (file.attrs(), reference.attrs());
+    if options.archive && (a.mode, a.uid, a.gid) != (b.mode, b.uid, b.gid) {
+        return None;
+    }
+    (!file.differs_from(&reference)).then_some(path)
+}
+
+impl Context {
+    fn new(options: &SyncOptions, src: &Path, dest: &Path) -> Context {
+        Context {
+            options: options.clone(),
+            src: src.to_path_buf(),
+            dest: dest.to_path_buf(),
+            leaders: HashMap::new(),
+            permits: Semaphore::new(options.jobs()),
+            protect: Protect::new(&options.filters),
+        }
+    }
+
+    fn finished(&self, path: &Path, bytes: u64) {
+        self.options.emit(SyncEvent::FileFinished {
+            path: path.to_path_buf(),
+            bytes,
+        });
+    }
+
+    /// Whether the destination entry at `rel` is protected, recording it if so.
+    fn protected(&self, report: &mut SyncReport, rel: &Path, is_dir: bool) -> bool {
+        if !self.protect.protects(rel, is_dir) {
+            return false;
+        }
+        let dest = self.dest.join(rel);
+        if self.options.verbose {
+            println!("{} is protected", dest.display());
+        }
+        report.protected.push(dest);
+        true
+    }
+
+    /// Record the failure of an operation on `path` and report it as an event.
+    fn fail(&self, report: &mut SyncReport, path: PathBuf, error: std::io::Error) {
+        self.options.emit(SyncEvent::Failed {
+            path: path.clone(),
+            error: error.to_string(),
+        });
+        report.fail(path, error);
+    }
+
+    /// The file at `rel` in the reference snapshot, if it is the same as `file`
+    /// and can be hard linked instead of copying `file`.
+    async fn reference(&self, file: &FnodeFile, rel: &Path) -> Option<PathBuf> {
+        let path = self.dest.join(self.options.link_dest.as_ref()?).join(rel);
+        let (file, options) = (file.clone(), self.options.clone());
+        tokio::task::spawn_blocking(move || same_reference(&file, path, &options))
+            .await
+            .ok()?
+    }
+
+    /// `reference`, reading the snapshot on the calling thread.
+    fn plan_reference(&self, file: &FnodeFile, rel: &Path) -> Option<PathBuf> {
+        let path = self.dest.join(self.options.link_dest.as_ref()?).join(rel);
+        same_reference(file, path, &self.options)
+    }
+
+    /// Where the destination file at `rel` is backed up to, if backups are enabled.
+    fn backup_path(&self, rel: &Path) -> Option<PathBuf> {
+        let dir = self.dest.join(self.options.backup_dir.as_ref()?);
+        let mut name = rel.as_os_str().to_owned();
+        name.push(&self.options.backup_suffix);
+        Some(dir.join(name))
+    }
+
+    async fn permit(&self) -> Option<SemaphorePermit<'_>> {
+        self.permits.acquire().await.ok()
+    }
+
+    /// The path `file` should be hard linked to instead of being copied, if any.
+    fn leader(&self, file: &FnodeFile, rel: &Path) -> Option<&PathBuf> {
+        if !self.options.hard_links {
+            return None;
+        }
+        let leader = self.leaders.get(&file.inode()?)?;
+        (leader != rel).then_some(leader)
+    }
+}
+
+/// Keep a copy of the destination file at `rel`, if any, in the backup directory.
+/// It is hard linked there, or copied when the backup directory is on another file system.
+async fn back_up(rel: &Path, ctx: &Context) -> std::io::Result<Option<PathBuf>> {
+    let backup = match ctx.backup_path(rel) {
+        Some(backup) => backup,
+        None => return Ok(None),
+    };
+    let dest = ctx.dest.join(rel);
+    let md = match tokio::fs::symlink_metadata(&dest).await {
+        Ok(md) => md,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if let Some(parent) = backup.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    match tokio::fs::remove_file(&backup).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    if tokio::fs::hard_link(&dest, &backup).await.is_err() {
+        if md.file_type().is_symlink() {
+            tokio::fs::symlink(tokio::fs::read_link(&dest).await?, &backup).await?;
+        } else {
+            tokio::fs::copy(&dest, &backup).await?;
+        }
+    }
+    if ctx.options.verbose {
+        println!("backed up {} to {}", dest.display(), backup.display());
+    }
+    Ok(Some(backup))
+}
+
+/// Back up `rel` before it is removed or replaced, recording the outcome in `report`.
+/// Returns false if the backup failed, in which case the file must be left alone.
+async fn backed_up(rel: &Path, ctx: &Context, report: &mut SyncReport) -> bool {
+    match back_up(rel, ctx).await {
+        Ok(backup) => {
+            report.backed_up.extend(backup);
+            true
+        }
+        Err(e) => {
+            ctx.fail(report, ctx.dest.join(rel), e);
+            false
+        }
+    }
+}
+
+fn find_leaders(dir: &FnodeDir, rel: &Path, leaders: &mut HashMap<(u64, u64), PathBuf>) {
+    for (n, c) in dir.children() {
+        match c.as_ref() {
+            Fnode::File(f) => {
+                if let Some(inode) = f.inode() {
+                    leaders.entry(inode).or_insert_with(|| rel.join(n));
+                }
+            }
+            Fnode::Dir(d) => find_leaders(d, &rel.join(n), leaders),
+            Fnode::Link(_) => {}
+        }
+    }
+}
+
+/// Run `f` over every child of `dir`, keeping at most `jobs` of them in flight.
+async fn for_children<F>(dir: &FnodeDir, rel: &Path, ctx: &Arc<Context>, f: F) -> SyncReport
+where
+    F: Fn(Arc<Fnode>, PathBuf, Arc<Context>) -> BoxFuture<'static, SyncReport>,
+{
+    let children: Vec<_> = dir
+        .children()
+        .iter()
+        .map(|(n, c)| f(c.clone(), rel.join(n), ctx.clone()))
+        .collect();
+    futures::stream::iter(children)
+        .buffer_unordered(ctx.options.jobs())
+        .collect()
+        .await
+}
+
+/// Remove `node` from the destination. `whole` tells whether a directory above it
+/// is removed as a whole, and so every directory below as well.
+fn remove_diff_node(
+    node: Arc<Fnode>,
+    rel: PathBuf,
+    ctx: Arc<Context>,
+    whole: bool,
+) -> BoxFuture<'static, SyncReport> {
     async move {
+        let mut report = SyncReport::default();
+        let dest = ctx.dest.join(&rel);
+        let is_dir = matches!(node.as_ref(), Fnode::Dir(_));
+        if ctx.protected(&mut report, &rel, is_dir) {
+            return report;
+        }
         match node.as_ref() {
-            Fnode::File(_) => {
-                if tokio::fs::remove_file(&dest).await.is_ok() && verbose {
-                    if let Some(path) = dest.to_str() {
-                        println!("file {} was removed", path);
+            Fnode::File(_) | Fnode::Link(_) => {
+                let _permit = ctx.permit().await;
+                if !backed_up(&rel, &ctx, &mut report).await {
+                    return report;
+                }
+                match tokio::fs::remove_file(&dest).await {
+                    Ok(()) => {
+                        if ctx.options.verbose {
+                            println!("file {} was removed", dest.display());
+                        }
+                        ctx.options.emit(SyncEvent::Removed(dest.clone()));
+                        report.removed_files.push(dest);
                     }
+                    Err(e) => ctx.fail(&mut report, dest, e),
                 }
             }
             Fnode::Dir(d) => {
-                futures::future::join_all(d.children().iter().map(|(n, c)| {
-                    let c = c.clone();
-                    let dest = dest.join(n);
-                    remove_diff_node(c, dest, verbose)
-                }))
+                let whole = whole || d.entirity();
+                report = for_children(d, &rel, &ctx, move |node, rel, ctx| {
+                    remove_diff_node(node, rel, ctx, whole)
+                })
                 .await;
-                if d.entirity() {
-                    if tokio::fs::remove_dir(&dest).await.is_ok() && verbose {
-                        if let Some(path) = dest.to_str() {
-                            println!("directory {} was removed", path);
+                // a directory holding protected entries stays
+                if whole && report.protected.is_empty() {
+                    let _permit = ctx.permit().await;
+                    match tokio::fs::remove_dir(&dest).await {
+                        Ok(()) => {
+                            if ctx.options.verbose {
+                                println!("directory {} was removed", dest.display());
+                            }
+                            ctx.options.emit(SyncEvent::Removed(dest.clone()));
+                            report.removed_dirs.push(dest);
                         }
+                        Err(e) => ctx.fail(&mut report, dest, e),
                     }
                 }
             }
         }
+        report
     }
     .boxed()
 }
 
-async fn remove_diff(diff: FnodeDir, dest: &PathBuf, verbose: bool) {
-    let dest = dest.clone();
-    remove_diff_node(Arc::new(Fnode::Dir(diff)), dest, verbose).await;
+async fn remove_diff(diff: FnodeDir, ctx: Arc<Context>) -> SyncReport {
+    remove_diff_node(Arc::new(Fnode::Dir(diff)), PathBuf::new(), ctx, false).await
 }
 
+async fn copy_file(f: &FnodeFile, rel: &Path, ctx: &Context) -> SyncReport {
+    let mut report = SyncReport::default();
+    let (src, dest) = (ctx.src.join(rel), ctx.dest.join(rel));
+    let _permit = ctx.permit().await;
+    if !backed_up(rel, ctx, &mut report).await {
+        return report;
+    }
+    ctx.options.emit(SyncEvent::FileStarted {
+        path: dest.clone(),
+        size: f.size(),
+    });
+    if let Some(reference) = ctx.reference(f, rel).await {
+        if link_atomic(&reference, &dest, &ctx.options).await.is_ok() {
+            if ctx.options.verbose {
+                println!("linked file {} to {}", dest.display(), reference.display());
+            }
+            ctx.finished(&dest, 0);
+            report.hard_linked.push(dest);
+            return report;
+        }
+    }
+    match copy_atomic(f, &src, &dest, &ctx.options).await {
+        Ok(bytes) => {
+            if ctx.options.verbose {
+                println!("copied file {} to {}", src.display(), dest.display());
+            }
+            ctx.finished(&dest, bytes);
+            report.bytes += bytes;
+            report.copied.push(dest);
+        }
+        Err(e) => ctx.fail(&mut report, dest, e),
+    }
+    report
+}
+
+/// Hard link `rel` to the already synced `leader` in the destination,
+/// falling back to a copy when the link cannot be made.
+async fn link_file(f: &FnodeFile, rel: &Path, leader: &Path, ctx: &Context) -> SyncReport {
+    let (leader, dest) = (ctx.dest.join(leader), ctx.dest.join(rel));
+    let mut report = SyncReport::default();
+    let permit = ctx.permit().await;
+    if !backed_up(rel, ctx, &mut report).await {
+        return report;
+    }
+    ctx.options.emit(SyncEvent::FileStarted {
+        path: dest.clone(),
+        size: f.size(),
+    });
+    let linked = link_atomic(&leader, &dest, &ctx.options).await;
+    drop(permit);
+    if linked.is_err() {
+        // the destination is untouched, so backing it up again is harmless
+        return copy_file(f, rel, ctx).await;
+    }
+    if ctx.options.verbose {
+        println!("linked file {} to {}", dest.display(), leader.display());
+    }
+    ctx.finished(&dest, 0);
+    report.hard_linked.push(dest);
+    report
+}
+
+/// Write `node` to the destination. `whole` tells whether a directory above it
+/// is created as a whole, and so every directory below as well.
 fn apply_diff_node(
     node: Arc<Fnode>,
-    src: PathBuf,
-    dest: PathBuf,
-    verbose: bool,
-) -> BoxFuture<'static, ()> {
+    rel: PathBuf,
+    ctx: Arc<Context>,
+    whole: bool,
+) -> BoxFuture<'static, SyncReport> {
     async move {
+        let mut report = SyncReport::default();
+        let dest = ctx.dest.join(&rel);
         match node.as_ref() {
-            Fnode::File(_) => {
-                if tokio::fs::copy(&src, &dest).await.is_ok() && verbose {
-                    (|| {
-                        println!("copied file {} to {}", src.to_str()?, dest.to_str()?);
-                        Some(())
-                    })();
+            Fnode::File(f) => {
+                // hard links are made once every file they could point to is in place
+                if ctx.leader(f, &rel).is_none() {
+                    report = copy_file(f, &rel, &ctx).await;
+                }
+            }
+            Fnode::Link(l) => {
+                let _permit = ctx.permit().await;
+                if !backed_up(&rel, &ctx, &mut report).await {
+                    return report;
+                }
+                ctx.options.emit(SyncEvent::FileStarted {
+                    path: dest.clone(),
+                    size: 0,
+                });
+                match make_link(l, &dest, &ctx.options).await {
+                    Ok(()) => {
+                        if ctx.options.verbose {
+                            println!("linked {} to {}", dest.display(), l.target().display());
+                        }
+                        ctx.finished(&dest, 0);
+                        report.copied.push(dest);
+                    }
+                    Err(e) => ctx.fail(&mut report, dest, e),
                 }
             }
             Fnode::Dir(d) => {
-                if !d.entirity() || tokio::fs::create_dir(&dest).await.is_ok() {
-                    futures::future::join_all(d.children().iter().map(|(n, c)| {
-                        let n = n.clone();
-                        let c = c.clone();
-                        let src = src.join(&n);
-                        let dest = dest.join(&n);
-                        let node = c.clone();
-                        apply_diff_node(node, src, dest, verbose)
-                    }))
-                    .await;
+                let whole = whole || d.entirity();
+                if whole {
+                    let _permit = ctx.permit().await;
+                    match tokio::fs::create_dir(&dest).await {
+                        Ok(()) => report.created_dirs.push(dest.clone()),
+                        // a directory left out of the destination scan, such as the
+                        // backup directory, is synced into as is
+                        Err(e)
+                            if e.kind() == std::io::ErrorKind::AlreadyExists
+                                && std::fs::symlink_metadata(&dest)
+                                    .map(|md| md.is_dir())
+                                    .unwrap_or(false) => {}
+                        Err(e) => {
+                            ctx.fail(&mut report, dest, e);
+                            return report;
+                        }
+                    }
+                }
+                let writes = d
+                    .children()
+                    .iter()
+                    .any(|(_, c)| !matches!(c.as_ref(), Fnode::Dir(_)));
+                if writes {
+                    if let Err(e) = make_partial_dir(&dest, &ctx.options).await {
+                        ctx.fail(&mut report, dest, e);
+                        return report;
+                    }
+                }
+                let children = for_children(d, &rel, &ctx, move |node, rel, ctx| {
+                    apply_diff_node(node, rel, ctx, whole)
+                });
+                report.merge(children.await);
+                if writes {
+                    remove_partial_dir(&dest, &ctx.options).await;
+                }
+                if let (true, Some(attrs)) = (ctx.options.archive, d.attrs()) {
+                    if let Err(e) = set_attrs(&dest, attrs) {
+                        ctx.fail(&mut report, dest, e);
+                    }
                 }
             }
         }
+        report
     }
     .boxed()
 }
 
-async fn apply_diff(diff: FnodeDir, src: &PathBuf, dest: &PathBuf, verbose: bool) {
-    let src = src.clone();
-    let dest = dest.clone();
-    apply_diff_node(Arc::new(Fnode::Dir(diff)), src, dest, verbose).await;
+/// Collect the files of `dir` that are to be hard linked to another synced file,
+/// along with the relative path of that file.
+fn find_followers(
+    dir: &FnodeDir,
+    rel: &Path,
+    ctx: &Context,
+    followers: &mut Vec<(FnodeFile, PathBuf, PathBuf)>,
+) {
+    for (n, c) in dir.children() {
+        let rel = rel.join(n);
+        match c.as_ref() {
+            Fnode::File(f) => {
+                if let Some(leader) = ctx.leader(f, &rel) {
+                    followers.push((f.clone(), leader.clone(), rel));
+                }
+            }
+            Fnode::Dir(d) => find_followers(d, &rel, ctx, followers),
+            Fnode::Link(_) => {}
+        }
+    }
 }
 
-pub fn arsygnore_parse(dir: &mut FnodeDir, text: String) {
-    for l in text.lines() {
-        let l = l.trim();
-        if let Some(last) = l.chars().last() {
-            let _ = dir.remove_path(PathBuf::from(l), last == '/');
+async fn apply_diff(diff: FnodeDir, ctx: Arc<Context>) -> SyncReport {
+    let mut followers = vec![];
+    find_followers(&diff, Path::new(""), &ctx, &mut followers);
+    let root = Arc::new(Fnode::Dir(diff));
+    let mut report = apply_diff_node(root, PathBuf::new(), ctx.clone(), false).await;
+    // the partial directories of the followers were removed along with their directories
+    let dirs: BTreeSet<PathBuf> = followers
+        .iter()
+        .filter_map(|(_, _, rel)| Some(ctx.dest.join(rel.parent()?)))
+        .collect();
+    for dir in dirs.iter() {
+        if let Err(e) = make_partial_dir(dir, &ctx.options).await {
+            ctx.fail(&mut report, dir.clone(), e);
         }
     }
+    report.merge(
+        futures::stream::iter(followers.iter())
+            .map(|(f, leader, rel)| link_file(f, rel, leader, &ctx))
+            .buffer_unordered(ctx.options.jobs())
+            .collect()
+            .await,
+    );
+    for dir in dirs.iter() {
+        remove_partial_dir(dir, &ctx.options).await;
+    }
+    report
 }
 
+fn plan_back_up(rel: &Path, ctx: &Context, report: &mut SyncReport) {
+    if let Some(backup) = ctx.backup_path(rel) {
+        let dest = ctx.dest.join(rel);
+        println!("back up {} to {}", dest.display(), backup.display());
+        report.backed_up.push(backup);
+    }
+}
+
+fn plan_remove_dir(
+    dir: &FnodeDir,
+    rel: &Path,
+    whole: bool,
+    ctx: &Context,
+    report: &mut SyncReport,
+) {
+    let whole = whole || dir.entirity();
+    let protected = report.protected.len();
+    for (n, c) in dir.children() {
+        let rel = rel.join(n);
+        let dest = ctx.dest.join(&rel);
+        if ctx.protected(report, &rel, matches!(c.as_ref(), Fnode::Dir(_))) {
+            continue;
+        }
+        match c.as_ref() {
+            Fnode::File(_) => {
+                plan_back_up(&rel, ctx, report);
+                println!("remove file {}", dest.display());
+                report.removed_files.push(dest);
+            }
+            Fnode::Link(_) => {
+                plan_back_up(&rel, ctx, report);
+                println!("remove symlink {}", dest.display());
+                report.removed_files.push(dest);
+            }
+            Fnode::Dir(d) => plan_remove_dir(d, &rel, whole, ctx, report),
+        }
+    }
+    if whole && report.protected.len() == protected {
+        println!("remove directory {}", ctx.dest.join(rel).display());
+        report.removed_dirs.push(ctx.dest.join(rel));
+    }
+}
+
+fn plan_apply_dir(
+    dir: &FnodeDir,
+    rel: &Path,
+    dest_tree: Option<&FnodeDir>,
+    whole: bool,
+    ctx: &Context,
+    report: &mut SyncReport,
+) {
+    let whole = whole || dir.entirity();
+    if whole {
+        println!("create directory {}", ctx.dest.join(rel).display());
+        report.created_dirs.push(ctx.dest.join(rel));
+    }
+    for (n, c) in dir.children() {
+        let rel = rel.join(n);
+        let (src, dest) = (ctx.src.join(&rel), ctx.dest.join(&rel));
+        match c.as_ref() {
+            Fnode::File(f) => {
+                let verb = match dest_tree.and_then(|t| t.file(n)) {
+                    Some(_) => {
+                        plan_back_up(&rel, ctx, report);
+                        "overwrite"
+                    }
+                    None => "create",
+                };
+                let leader = ctx
+                    .leader(f, &rel)
+                    .map(|leader| ctx.dest.join(leader))
+                    .or_else(|| ctx.plan_reference(f, &rel));
+                if let Some(leader) = leader {
+                    println!(
+                        "{} file {} as link to {}",
+                        verb,
+                        dest.display(),
+                        leader.display()
+                    );
+                    report.hard_linked.push(dest);
+                } else {
+                    println!("{} file {} from {}", verb, dest.display(), src.display());
+                    report.copied.push(dest);
+                    report.bytes += f.size();
+                }
+            }
+            Fnode::Link(l) => {
+                let verb = match dest_tree.and_then(|t| t.get(n)) {
+                    Some(_) => {
+                        plan_back_up(&rel, ctx, report);
+                        "replace"
+                    }
+                    None => "create",
+                };
+                println!(
+                    "{} symlink {} -> {}",
+                    verb,
+                    dest.display(),
+                    l.target().display()
+                );
+                report.copied.push(dest);
+            }
+            Fnode::Dir(d) => {
+                let dest_tree = dest_tree.and_then(|t| t.subdir(n));
+                plan_apply_dir(d, &rel, dest_tree, whole, ctx, report)
+            }
+        }
+    }
+}
+
+/// Remove the entries of `dir` that the ignore file `text` excludes.
+pub fn arsygnore_parse(dir: &mut FnodeDir, text: &[u8]) {
+    Ignore::parse(text).prune(dir, Path::new(""));
+}
+
+/// `path` relative to `root`, if it lies inside it.
+fn inside(root: &Path, path: &Path) -> Option<PathBuf> {
+    let normal = |rel: &Path| rel.components().all(|c| matches!(c, Component::Normal(_)));
+    if let Ok(rel) = path.strip_prefix(root) {
+        if normal(rel) {
+            return Some(rel.to_path_buf());
+        }
+    }
+    let (root, path) = (root.canonicalize().ok()?, path.canonicalize().ok()?);
+    let rel = path.strip_prefix(root).ok()?;
+    normal(rel).then(|| rel.to_path_buf())
+}
+
+/// Rules leaving paths of `root` out of a sync to `dest`: `ignore`, which the ignore
+/// files found in the tree add to, then the filters of `options`, and arsync's own state
+/// directory and the backup directory, if `root` holds it, which always win.
+fn ignore_rules(
+    root: &Path,
+    dest: &Path,
+    ignore: Option<&[u8]>,
+    options: &SyncOptions,
+) -> Arc<Ignore> {
+    let mut rules = ignore.map(Ignore::parse).unwrap_or_default();
+    rules.filter(&options.filters);
+    rules.exclude(Path::new(bidi::STATE_DIR), false);
+    if let Some(backup) = &options.backup_dir {
+        // the backup directory lies in the destination unless an absolute path puts it
+        // elsewhere, maybe in the source
+        if let Some(rel) = inside(root, &dest.join(backup)) {
+            rules.exclude(&rel, true);
+        }
+    }
+    Arc::new(rules)
+}
+
+/// Traverse `root`, leaving out what its `ignore_rules` for a sync to `dest` exclude.
+async fn scan_tree(
+    root: &Path,
+    dest: &Path,
+    ignore: Option<&[u8]>,
+    links: LinkPolicy,
+    limits: Limits,
+    options: &SyncOptions,
+) -> Option<(FnodeDir, SyncReport)> {
+    let ignore = ignore_rules(root, dest, ignore, options);
+    traverse_dir(root, links, limits, ignore, options).await
+}
+
+/// Sync `src` into `dest` according to `options`. `src_ignore` and `dest_ignore` are
+/// ignore rules for the root of each tree, before those of its `.arsygnore` files.
 pub async fn sync_dirs(
-    src: &PathBuf,
-    dest: &PathBuf,
-    src_ignore: Option<String>,
-    dest_ignore: Option<String>,
-    verbose: bool,
-    mode: SyncMode,
-) -> Result<(), u8> {
-    let mut src_tree = traverse_dir(src).ok_or(1)?;
-    if let Some(text) = src_ignore {
-        arsygnore_parse(&mut src_tree, text);
-    }
-    let mut dest_tree = traverse_dir(dest).ok_or(2)?;
-    if let Some(text) = dest_ignore {
-        arsygnore_parse(&mut dest_tree, text);
-    }
-    let (add_diff, rem_diff) = match mode {
+    src: &Path,
+    dest: &Path,
+    src_ignore: Option<Vec<u8>>,
+    dest_ignore: Option<Vec<u8>>,
+    options: &SyncOptions,
+) -> Result<SyncReport, SyncError> {
+    check_partial_dir(dest, options)?;
+    if let SyncMode::Bidirectional = options.mode {
+        check_partial_dir(src, options)?;
+    }
+    if options.streaming && !matches!(options.mode, SyncMode::Bidirectional) {
+        let (src_ignore, dest_ignore) = (src_ignore.as_deref(), dest_ignore.as_deref());
+        return stream::sync_stream(src, dest, src_ignore, dest_ignore, options).await;
+    }
+    // links in the destination are never followed, so nothing is written through them,
+    // unless both sides are sources
+    let dest_links = match options.mode {
+        SyncMode::Bidirectional => options.links,
+        _ => LinkPolicy::Preserve,
+    };
+    // a file left out of one side would look deleted to a bidirectional sync
+    let src_limits = match options.mode {
+        SyncMode::Bidirectional => Limits::default(),
+        _ => options.limits,
+    };
+    let (src_tree, dest_tree) = tokio::join!(
+        scan_tree(
+            src,
+            dest,
+            src_ignore.as_deref(),
+            options.links,
+            src_limits,
+            options
+        ),
+        scan_tree(
+            dest,
+            dest,
+            dest_ignore.as_deref(),
+            dest_links,
+            Limits::default(),
+            options
+        )
+    );
+    let (src_tree, mut scanned) = src_tree.ok_or(SyncError::Source)?;
+    let (dest_tree, dest_scanned) = dest_tree.ok_or(SyncError::Destination)?;
+    scanned.merge(dest_scanned);
+    let (add_diff, rem_diff) = match options.mode {
         SyncMode::Soft => calc_diff_soft(&src_tree, &dest_tree, false),
         SyncMode::Mixed => calc_diff_soft(&src_tree, &dest_tree, true),
         SyncMode::Hard => calc_diff_hard(&src_tree, &dest_tree),
         SyncMode::Update => (calc_diff_update(&src_tree, &dest_tree), FnodeDir::default()),
+        SyncMode::Bidirectional => {
+            let (src_ignore, dest_ignore) = (src_ignore.as_deref(), dest_ignore.as_deref());
+            return bidi::sync_bidi(
+                src,
+                dest,
+                src_tree,
+                dest_tree,
+                src_ignore,
+                dest_ignore,
+                options,
+            )
+            .await
+            .map(|mut report| {
+                report.merge(scanned);
+                report
+            });
+        }
     };
-    remove_diff(rem_diff, dest, verbose).await;
-    apply_diff(add_diff, src, dest, verbose).await;
-    Ok(())
+    let (files, bytes) = add_diff.totals();
+    options.emit(SyncEvent::Planned { files, bytes });
+    let mut ctx = Context::new(options, src, dest);
+    if options.hard_links {
+        find_leaders(&src_tree, Path::new(""), &mut ctx.leaders);
+    }
+    let mut report = scanned;
+    if options.dry_run {
+        plan_remove_dir(&rem_diff, Path::new(""), false, &ctx, &mut report);
+        plan_apply_dir(
+            &add_diff,
+            Path::new(""),
+            Some(&dest_tree),
+            false,
+            &ctx,
+            &mut report,
+        );
+        return Ok(report);
+    }
+    let ctx = Arc::new(ctx);
+    report.merge(remove_diff(rem_diff, ctx.clone()).await);
+    report.merge(apply_diff(add_diff, ctx).await);
+    Ok(report)
 }
@@ -1,10 +1,29 @@
-use arsync::{sync_dirs, SyncMode};
-use clap::Parser;
-use std::{path::PathBuf, process::exit};
+use arsync::{
+    prune_snapshots, sync_dirs, ConflictPolicy, Filter, Limits, LinkPolicy, Resolution, Retention,
+    SyncError, SyncEvent, SyncMode, SyncOptions, DEFAULT_JOBS,
+};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    os::unix::ffi::OsStringExt,
+    path::PathBuf,
+    process::exit,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 
 #[derive(Parser, Debug)]
-#[clap(version = "0.1.0", about = "file synchronization utility")]
+#[clap(
+    version = "0.1.0",
+    about = "file synchronization utility",
+    args_conflicts_with_subcommands = true
+)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     #[clap(help = "source directory")]
     src: Option<PathBuf>,
 
@@ -23,8 +42,239 @@ struct Args {
     #[clap(short, long)]
     mixed: bool,
 
+    #[clap(short, long, help = "sync changes made on either side to the other")]
+    bidirectional: bool,
+
     #[clap(short, long)]
     verbose: bool,
+
+    #[clap(
+        short = 'n',
+        long,
+        help = "print what would be done without changing anything"
+    )]
+    dry_run: bool,
+
+    #[clap(
+        short,
+        long,
+        help = "compare files by content hash instead of modification time"
+    )]
+    checksum: bool,
+
+    #[clap(short, long, help = "preserve times, permissions and ownership")]
+    archive: bool,
+
+    #[clap(
+        short,
+        long,
+        default_value = "skip",
+        possible_values = ["skip", "preserve", "follow", "internal"],
+        help = "how to sync symbolic links"
+    )]
+    links: String,
+
+    #[clap(short = 'H', long, help = "preserve hard links")]
+    hard_links: bool,
+
+    #[clap(
+        long,
+        help = "flush every copied file to disk before it replaces the destination"
+    )]
+    fsync: bool,
+
+    #[clap(
+        long,
+        help = "directory to keep files in while they are written, on the destination file system"
+    )]
+    partial_dir: Option<PathBuf>,
+
+    #[clap(short, long, default_value_t = DEFAULT_JOBS, help = "how many files to copy or remove at once")]
+    jobs: usize,
+
+    #[clap(
+        long,
+        default_value = "newest",
+        possible_values = ["newest", "source", "keep-both", "abort"],
+        help = "how to resolve paths changed on both sides in bidirectional mode"
+    )]
+    conflicts: String,
+
+    #[clap(
+        long,
+        help = "directory to move removed and replaced destination files to"
+    )]
+    backup_dir: Option<PathBuf>,
+
+    #[clap(
+        long,
+        requires = "backup-dir",
+        default_value = "",
+        help = "suffix appended to the names of backed up files"
+    )]
+    suffix: String,
+
+    #[clap(long, help = "previous snapshot to hard link unchanged files from")]
+    link_dest: Option<PathBuf>,
+
+    #[clap(short = 'P', long, help = "show a progress bar while syncing")]
+    progress: bool,
+
+    #[clap(
+        long,
+        help = "sync one directory at a time to bound memory use, without hard link detection"
+    )]
+    stream: bool,
+
+    #[clap(
+        long,
+        value_name = "PATTERN",
+        help = "leave paths matching the pattern out of the sync",
+        parse(from_os_str)
+    )]
+    exclude: Vec<OsString>,
+
+    #[clap(
+        long,
+        value_name = "PATTERN",
+        help = "sync paths matching the pattern even if excluded",
+        parse(from_os_str)
+    )]
+    include: Vec<OsString>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "read exclude patterns from a file, one per line"
+    )]
+    exclude_from: Vec<PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "read include patterns from a file, one per line"
+    )]
+    include_from: Vec<PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "PATTERN",
+        help = "never remove destination paths matching the pattern",
+        parse(from_os_str)
+    )]
+    protect: Vec<OsString>,
+
+    #[clap(
+        long,
+        value_name = "SIZE",
+        parse(try_from_str = parse_size),
+        help = "skip source files larger than this, such as 500K or 2G"
+    )]
+    max_size: Option<u64>,
+
+    #[clap(
+        long,
+        value_name = "SIZE",
+        parse(try_from_str = parse_size),
+        help = "skip source files smaller than this"
+    )]
+    min_size: Option<u64>,
+
+    #[clap(
+        long,
+        value_name = "AGE",
+        parse(try_from_str = parse_age),
+        help = "skip source files modified longer ago than this, such as 30m or 7d"
+    )]
+    newer_than: Option<Duration>,
+
+    #[clap(
+        long,
+        value_name = "AGE",
+        parse(try_from_str = parse_age),
+        help = "skip source files modified more recently than this"
+    )]
+    older_than: Option<Duration>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Remove the dated snapshot directories not kept by the retention policy
+    Prune(PruneArgs),
+}
+
+#[derive(Parser, Debug)]
+struct PruneArgs {
+    #[clap(help = "directory holding the snapshots")]
+    dir: PathBuf,
+
+    #[clap(long, default_value_t = 0, help = "number of hourly snapshots to keep")]
+    hourly: usize,
+
+    #[clap(long, default_value_t = 0, help = "number of daily snapshots to keep")]
+    daily: usize,
+
+    #[clap(long, default_value_t = 0, help = "number of weekly snapshots to keep")]
+    weekly: usize,
+
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "number of monthly snapshots to keep"
+    )]
+    monthly: usize,
+
+    #[clap(short, long)]
+    verbose: bool,
+
+    #[clap(
+        short = 'n',
+        long,
+        help = "print which snapshots would be pruned without removing them"
+    )]
+    dry_run: bool,
+}
+
+/// Parse a number followed by an optional unit from `units`, which it is multiplied by.
+fn with_unit(value: &str, units: &[(char, u64)]) -> Result<u64, String> {
+    let (digits, factor) = match value.chars().last() {
+        Some(c) if c.is_ascii_alphabetic() => {
+            let unit = c.to_ascii_lowercase();
+            match units.iter().find(|(u, _)| *u == unit) {
+                Some((_, factor)) => (&value[..value.len() - 1], *factor),
+                None => return Err(format!("unknown unit '{}'", c)),
+            }
+        }
+        _ => (value, 1),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid number '{}'", digits))?;
+    n.checked_mul(factor)
+        .ok_or_else(|| String::from("value too large"))
+}
+
+/// A size in bytes, with an optional K, M, G or T suffix for powers of 1024.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let units = [
+        ('k', 1 << 10),
+        ('m', 1 << 20),
+        ('g', 1 << 30),
+        ('t', 1 << 40),
+    ];
+    with_unit(value, &units)
+}
+
+/// A duration in seconds, with an optional s, m, h, d or w suffix.
+fn parse_age(value: &str) -> Result<Duration, String> {
+    let units = [
+        ('s', 1),
+        ('m', 60),
+        ('h', 3600),
+        ('d', 86400),
+        ('w', 604800),
+    ];
+    with_unit(value, &units).map(Duration::from_secs)
 }
 
 fn err(str: &str) -> ! {
@@ -35,9 +285,121 @@ fn err(str: &str) -> ! {
 const ERR_SRC: &str = "Error: invalid source directory";
 const ERR_DEST: &str = "Error: invalid destination directory";
 
+/// Draw a progress bar from the events of a sync until it ends.
+async fn show_progress(mut events: UnboundedReceiver<SyncEvent>) {
+    let bar = ProgressBar::new(0);
+    bar.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} {msg} eta {eta}",
+        )
+        .unwrap_or_else(|_| ProgressStyle::default_bar()),
+    );
+    let (mut files, mut total) = (0, 0);
+    // bytes written so far of the files being copied
+    let mut written: HashMap<PathBuf, u64> = HashMap::new();
+    while let Some(event) = events.recv().await {
+        match event {
+            SyncEvent::ScanStarted(root) => bar.set_message(format!("scanning {}", root.display())),
+            SyncEvent::Planned { files, bytes } => {
+                total = files;
+                bar.set_length(bytes);
+            }
+            SyncEvent::FileProgress { path, bytes } => {
+                let before = written.insert(path, bytes).unwrap_or(0);
+                bar.inc(bytes.saturating_sub(before));
+            }
+            SyncEvent::FileFinished { path, bytes } => {
+                let before = written.remove(&path).unwrap_or(0);
+                bar.inc(bytes.saturating_sub(before));
+                files += 1;
+            }
+            _ => {}
+        }
+        if total > 0 {
+            bar.set_message(format!("{}/{} files", files, total));
+        }
+    }
+    bar.finish();
+}
+
+/// The filters given on the command line, in the order they were given.
+fn filters(matches: &ArgMatches, args: &Args) -> Vec<Filter> {
+    let indices = |id: &str| matches.indices_of(id).into_iter().flatten();
+    // patterns are matched against names, which need not be UTF-8
+    let read = |path: &PathBuf| -> Vec<OsString> {
+        let text = std::fs::read(path)
+            .unwrap_or_else(|_| err(&format!("Error: cannot read {}", path.display())));
+        text.split(|b| *b == b'\n')
+            .map(|l| l.strip_suffix(b"\r").unwrap_or(l))
+            .map(|l| OsString::from_vec(l.to_vec()))
+            .collect()
+    };
+    let mut filters: Vec<(usize, Filter)> = vec![];
+    for (i, pattern) in indices("exclude").zip(&args.exclude) {
+        filters.push((i, Filter::Exclude(pattern.clone())));
+    }
+    for (i, pattern) in indices("include").zip(&args.include) {
+        filters.push((i, Filter::Include(pattern.clone())));
+    }
+    for (i, pattern) in indices("protect").zip(&args.protect) {
+        filters.push((i, Filter::Protect(pattern.clone())));
+    }
+    for (i, path) in indices("exclude-from").zip(&args.exclude_from) {
+        let lines = read(path)
+            .into_iter()
+            .map(|l| (i, Filter::Exclude(l)))
+            .collect::<Vec<_>>();
+        filters.extend(lines);
+    }
+    for (i, path) in indices("include-from").zip(&args.include_from) {
+        let lines = read(path)
+            .into_iter()
+            .map(|l| (i, Filter::Include(l)))
+            .collect::<Vec<_>>();
+        filters.extend(lines);
+    }
+    // the sort is stable, so the lines of a file stay in order
+    filters.sort_by_key(|(i, _)| *i);
+    filters.into_iter().map(|(_, f)| f).collect()
+}
+
+async fn prune(args: PruneArgs) {
+    let retention = Retention {
+        hourly: args.hourly,
+        daily: args.daily,
+        weekly: args.weekly,
+        monthly: args.monthly,
+    };
+    if retention.hourly + retention.daily + retention.weekly + retention.monthly == 0 {
+        err("Error: no snapshots to keep, use at least one of 'hourly', 'daily', 'weekly' and 'monthly'");
+    }
+    let options = SyncOptions {
+        verbose: args.verbose,
+        dry_run: args.dry_run,
+        ..Default::default()
+    };
+    match prune_snapshots(&args.dir, &retention, &options).await {
+        Err(_) => err("Error: invalid snapshot directory"),
+        Ok(report) => {
+            for (path, e) in report.failures.iter() {
+                println!("Error: {}: {}", path.display(), e);
+            }
+            if !report.is_complete() {
+                exit(1);
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let filters = filters(&matches, &args);
+    if let Some(Command::Prune(prune_args)) = args.command {
+        prune(prune_args).await;
+        return;
+    }
     let src = args
         .src
         .unwrap_or_else(|| err("Error: source directory not provided"))
@@ -49,9 +411,6 @@ async fn main() {
         .canonicalize()
         .unwrap_or_else(|_| err(ERR_SRC));
 
-    let src_ignore = std::fs::read_to_string(src.join(".arsygnore")).ok();
-    let dest_ignore = std::fs::read_to_string(dest.join(".arsygnore")).ok();
-
     if std::fs::metadata(&src)
         .unwrap_or_else(|_| err(ERR_SRC))
         .is_file()
@@ -65,26 +424,125 @@ async fn main() {
         err(ERR_DEST);
     }
 
-    let flags = [args.update, args.soft, args.mixed, args.hard];
+    let flags = [
+        args.update,
+        args.soft,
+        args.mixed,
+        args.hard,
+        args.bidirectional,
+    ];
     if flags.iter().filter(|f| **f).count() > 1 {
-        err("can only use one of 'update' , 'soft' , 'mixed' and 'hard' flags");
+        err("can only use one of 'update' , 'soft' , 'mixed' , 'hard' and 'bidirectional' flags");
     }
 
+    if args.stream && (args.hard_links || args.bidirectional) {
+        err("can not use 'stream' with 'hard-links' or 'bidirectional' flags");
+    }
+
+    let limited = args.max_size.is_some()
+        || args.min_size.is_some()
+        || args.newer_than.is_some()
+        || args.older_than.is_some();
+    if limited && args.bidirectional {
+        err("can not use size or age limits with the 'bidirectional' flag");
+    }
+    let now = SystemTime::now();
+    let cutoff = |age: Option<Duration>| age.and_then(|age| now.checked_sub(age));
+
     let mode = if args.hard {
         SyncMode::Hard
     } else if args.soft {
         SyncMode::Soft
     } else if args.update {
         SyncMode::Update
+    } else if args.bidirectional {
+        SyncMode::Bidirectional
     } else {
         SyncMode::Mixed
     };
 
-    if let Err(index) = sync_dirs(&src, &dest, src_ignore, dest_ignore, args.verbose, mode).await {
-        if index == 1 {
-            err(ERR_SRC);
-        } else {
-            err(ERR_DEST);
+    let options = SyncOptions {
+        mode,
+        verbose: args.verbose,
+        dry_run: args.dry_run,
+        checksum: args.checksum,
+        archive: args.archive,
+        links: match args.links.as_str() {
+            "preserve" => LinkPolicy::Preserve,
+            "follow" => LinkPolicy::Follow,
+            "internal" => LinkPolicy::Internal,
+            _ => LinkPolicy::Skip,
+        },
+        hard_links: args.hard_links,
+        fsync: args.fsync,
+        partial_dir: args.partial_dir,
+        jobs: args.jobs,
+        conflicts: match args.conflicts.as_str() {
+            "source" => ConflictPolicy::Source,
+            "keep-both" => ConflictPolicy::KeepBoth,
+            "abort" => ConflictPolicy::Abort,
+            _ => ConflictPolicy::Newest,
+        },
+        backup_dir: args.backup_dir,
+        backup_suffix: args.suffix,
+        link_dest: args.link_dest,
+        events: None,
+        streaming: args.stream,
+        filters,
+        limits: Limits {
+            min_size: args.min_size,
+            max_size: args.max_size,
+            newer_than: cutoff(args.newer_than),
+            older_than: cutoff(args.older_than),
+        },
+    };
+
+    let result = if args.progress {
+        let (events, receiver) = unbounded_channel();
+        let progress = tokio::spawn(show_progress(receiver));
+        let options = SyncOptions {
+            events: Some(events),
+            ..options
+        };
+        let result = sync_dirs(&src, &dest, None, None, &options).await;
+        drop(options);
+        let _ = progress.await;
+        result
+    } else {
+        sync_dirs(&src, &dest, None, None, &options).await
+    };
+    match result {
+        Err(SyncError::Source) => err(ERR_SRC),
+        Err(SyncError::Destination) => err(ERR_DEST),
+        Err(SyncError::PartialDir) => {
+            err("Error: the partial directory is not on the destination file system")
+        }
+        Err(SyncError::Conflict(conflicts)) => {
+            for c in conflicts.iter() {
+                println!("conflict: {}", c.path.display());
+            }
+            err("Error: sync aborted because of conflicts")
+        }
+        Ok(report) => {
+            for c in report.conflicts.iter() {
+                match &c.resolution {
+                    Resolution::KeepBoth(copy) => println!(
+                        "conflict: {} (destination version kept as {})",
+                        c.path.display(),
+                        copy.display()
+                    ),
+                    Resolution::Destination => {
+                        println!("conflict: {} (kept destination version)", c.path.display())
+                    }
+                    _ => println!("conflict: {} (kept source version)", c.path.display()),
+                }
+            }
+            for (path, e) in report.failures.iter() {
+                println!("Error: {}: {}", path.display(), e);
+            }
+            if !report.is_complete() {
+                exit(1);
+            }
         }
     }
 }
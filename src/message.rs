@@ -31,7 +31,7 @@ impl Messenger {
     }
     pub async fn recv(&mut self) -> Result<Buffer, ()> {
         let buffer = self.read_buffer().await?;
-        if buffer.len() == 0 {
+        if buffer.is_empty() {
             Ok(Buffer::End)
         } else if buffer == "init".to_string().as_bytes() {
             Ok(Buffer::Message(Message::Init))
@@ -0,0 +1,161 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    ftree::FnodeDir, remove_diff, scan_removal, Context, SyncError, SyncOptions, SyncReport,
+};
+
+/// How many snapshots to keep for each period, counting back from the newest one.
+/// The newest snapshot of each of the last `daily` days is kept, and so on.
+#[derive(Clone, Copy, Default)]
+pub struct Retention {
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+/// Time a snapshot was taken, read from its name.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Stamp {
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+}
+
+impl Stamp {
+    /// Parse the digits of a dated name, such as `2022-04-01` or `20220401T0300`,
+    /// as year, month, day and optionally hour and minute.
+    fn parse(name: &str) -> Option<Stamp> {
+        let digits: Vec<i64> = name
+            .chars()
+            .filter_map(|c| c.to_digit(10).map(i64::from))
+            .collect();
+        let number = |from: usize, len: usize| -> Option<i64> {
+            let digits = digits.get(from..from + len)?;
+            Some(digits.iter().fold(0, |n, d| n * 10 + d))
+        };
+        let stamp = Stamp {
+            year: number(0, 4)?,
+            month: number(4, 2)?,
+            day: number(6, 2)?,
+            hour: number(8, 2).unwrap_or(0),
+            minute: number(10, 2).unwrap_or(0),
+        };
+        let valid = (1..=12).contains(&stamp.month)
+            && (1..=31).contains(&stamp.day)
+            && stamp.hour < 24
+            && stamp.minute < 60;
+        valid.then_some(stamp)
+    }
+
+    /// Days since the epoch.
+    fn days(&self) -> i64 {
+        let year = if self.month <= 2 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let doy = (153 * ((self.month + 9) % 12) + 2) / 5 + self.day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    fn hour(&self) -> i64 {
+        self.days() * 24 + self.hour
+    }
+
+    /// Weeks since the epoch, starting on mondays.
+    fn week(&self) -> i64 {
+        (self.days() + 3).div_euclid(7)
+    }
+
+    fn month(&self) -> i64 {
+        self.year * 12 + self.month
+    }
+}
+
+/// Indices of the snapshots, sorted newest first, that `retention` keeps.
+fn retained(snapshots: &[(Stamp, String)], retention: &Retention) -> BTreeSet<usize> {
+    let periods = [
+        (retention.hourly, Stamp::hour as fn(&Stamp) -> i64),
+        (retention.daily, Stamp::days),
+        (retention.weekly, Stamp::week),
+        (retention.monthly, Stamp::month),
+    ];
+    let mut kept = BTreeSet::new();
+    for (count, period) in periods {
+        let mut last = None;
+        let mut seen = 0;
+        for (i, (stamp, _)) in snapshots.iter().enumerate() {
+            if seen == count {
+                break;
+            }
+            let p = period(stamp);
+            if last != Some(p) {
+                kept.insert(i);
+                last = Some(p);
+                seen += 1;
+            }
+        }
+    }
+    kept
+}
+
+/// Remove the dated snapshot directories of `root` not kept by `retention`.
+/// Directories whose name holds no date are left alone.
+pub async fn prune_snapshots(
+    root: &Path,
+    retention: &Retention,
+    options: &SyncOptions,
+) -> Result<SyncReport, SyncError> {
+    let mut snapshots: Vec<(Stamp, String)> = std::fs::read_dir(root)
+        .map_err(|_| SyncError::Destination)?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter_map(|name| Some((Stamp::parse(&name)?, name)))
+        .collect();
+    snapshots.sort_by(|a, b| b.cmp(a));
+    let kept = retained(&snapshots, retention);
+
+    let mut report = SyncReport::default();
+    let mut diff = FnodeDir::default();
+    for (i, (_, name)) in snapshots.iter().enumerate() {
+        let path = root.join(name);
+        if kept.contains(&i) {
+            if options.dry_run {
+                println!("keep snapshot {}", path.display());
+            }
+            continue;
+        }
+        if options.dry_run {
+            println!("prune snapshot {}", path.display());
+            report.removed_dirs.push(path);
+            continue;
+        }
+        match scan_removal(&path).await {
+            Some((mut tree, scanned)) => {
+                tree.set_entirity(true);
+                diff.append_dir(name.into(), tree);
+                report.merge(scanned);
+            }
+            None => report.fail(
+                PathBuf::from(&path),
+                std::io::Error::new(std::io::ErrorKind::NotFound, "cannot scan snapshot"),
+            ),
+        }
+    }
+    if !options.dry_run {
+        let ctx = Arc::new(Context::new(options, root, root));
+        report.merge(remove_diff(diff, ctx).await);
+    }
+    Ok(report)
+}
@@ -0,0 +1,120 @@
+use std::{io, path::PathBuf};
+
+#[derive(Debug)]
+pub enum SyncError {
+    /// the source directory could not be read
+    Source,
+    /// the destination directory could not be read
+    Destination,
+    /// the absolute partial directory is on another file system than a directory
+    /// files are written to, so they could not be renamed into place
+    PartialDir,
+    /// paths changed on both sides of a bidirectional sync, which was aborted without changes
+    Conflict(Vec<Conflict>),
+}
+
+/// A path modified on both replicas since the last bidirectional sync.
+#[derive(Debug)]
+pub struct Conflict {
+    /// path relative to the root of both replicas
+    pub path: PathBuf,
+    pub resolution: Resolution,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Resolution {
+    /// the source version replaced the destination one
+    Source,
+    /// the destination version replaced the source one
+    Destination,
+    /// the source version was kept under the original path and the destination one under this path
+    KeepBoth(PathBuf),
+    /// the sync was aborted
+    Abort,
+}
+
+/// Sent while a sync runs, through `SyncOptions::events`, to follow its progress.
+#[derive(Debug)]
+pub enum SyncEvent {
+    /// a directory tree started being scanned
+    ScanStarted(PathBuf),
+    /// a directory tree was scanned, holding this many files and links and bytes in files
+    /// (once the sync is done, when streaming)
+    ScanFinished {
+        root: PathBuf,
+        files: usize,
+        bytes: u64,
+    },
+    /// the diff was computed and this many files and bytes are to be written
+    /// (after each directory, with the running totals, when streaming)
+    Planned { files: usize, bytes: u64 },
+    /// a file, link or hard link started being written
+    FileStarted { path: PathBuf, size: u64 },
+    /// this many bytes of a file were written so far
+    FileProgress { path: PathBuf, bytes: u64 },
+    /// a file was written, copying this many bytes
+    FileFinished { path: PathBuf, bytes: u64 },
+    /// a file, link or directory was removed
+    Removed(PathBuf),
+    /// an operation on a path failed
+    Failed { path: PathBuf, error: String },
+}
+
+/// Everything a sync did (or, in dry-run mode, would do) to the destination.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub copied: Vec<PathBuf>,
+    pub hard_linked: Vec<PathBuf>,
+    pub created_dirs: Vec<PathBuf>,
+    pub removed_files: Vec<PathBuf>,
+    pub removed_dirs: Vec<PathBuf>,
+    /// where removed or replaced destination files were backed up to
+    pub backed_up: Vec<PathBuf>,
+    /// destination paths that would have been removed, left in place because they are protected
+    pub protected: Vec<PathBuf>,
+    pub bytes: u64,
+    pub failures: Vec<(PathBuf, io::Error)>,
+    pub conflicts: Vec<Conflict>,
+}
+
+impl SyncReport {
+    pub fn merge(&mut self, other: SyncReport) {
+        self.copied.extend(other.copied);
+        self.hard_linked.extend(other.hard_linked);
+        self.created_dirs.extend(other.created_dirs);
+        self.removed_files.extend(other.removed_files);
+        self.removed_dirs.extend(other.removed_dirs);
+        self.backed_up.extend(other.backed_up);
+        self.protected.extend(other.protected);
+        self.bytes += other.bytes;
+        self.failures.extend(other.failures);
+        self.conflicts.extend(other.conflicts);
+    }
+
+    pub fn fail(&mut self, path: PathBuf, error: io::Error) {
+        self.failures.push((path, error));
+    }
+
+    /// true if no operation failed
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl Extend<SyncReport> for SyncReport {
+    fn extend<I: IntoIterator<Item = SyncReport>>(&mut self, iter: I) {
+        for r in iter {
+            self.merge(r);
+        }
+    }
+}
+
+impl FromIterator<SyncReport> for SyncReport {
+    fn from_iter<I: IntoIterator<Item = SyncReport>>(iter: I) -> SyncReport {
+        let mut report = SyncReport::default();
+        for r in iter {
+            report.merge(r);
+        }
+        report
+    }
+}
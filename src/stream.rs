@@ -0,0 +1,308 @@
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+
+use crate::{
+    apply_diff_node, calc_diff_hard, calc_diff_soft, calc_diff_update,
+    ftree::{Fnode, FnodeDir},
+    ignore::Ignore,
+    ignore_rules, plan_apply_dir, plan_remove_dir, read_listing, remove_diff_node, set_attrs,
+    Context, Limits, LinkPolicy, Listing, Scan, Scanned, SyncError, SyncEvent, SyncMode,
+    SyncOptions, SyncReport,
+};
+
+/// How a directory is synced.
+#[derive(Clone, Copy, PartialEq)]
+enum Walk {
+    /// it is on both sides
+    Both,
+    /// it is only in the source and is created in the destination
+    Create,
+    /// it is only in the destination and is removed from it
+    Remove,
+}
+
+/// Numbers of files and links, and bytes in files, of the directories synced so far.
+#[derive(Default)]
+struct Totals {
+    src: (usize, u64),
+    dest: (usize, u64),
+    /// what is to be written to the destination
+    planned: (usize, u64),
+}
+
+/// State shared by every directory of one streaming sync.
+struct Stream {
+    ctx: Arc<Context>,
+    src: Arc<Scan>,
+    dest: Arc<Scan>,
+    totals: Mutex<Totals>,
+}
+
+impl Stream {
+    /// Count the listings of one directory and what is to be written to it,
+    /// announcing the running totals of the plan when they grow.
+    fn tally(&self, src: &FnodeDir, dest: &FnodeDir, add: &FnodeDir) {
+        let count = |total: &mut (usize, u64), (files, bytes): (usize, u64)| {
+            total.0 += files;
+            total.1 += bytes;
+        };
+        let mut totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+        count(&mut totals.src, src.totals());
+        count(&mut totals.dest, dest.totals());
+        let added = add.totals();
+        if added.0 > 0 {
+            count(&mut totals.planned, added);
+            let (files, bytes) = totals.planned;
+            self.ctx.options.emit(SyncEvent::Planned { files, bytes });
+        }
+    }
+}
+
+/// The directory at `rel` with its files and links, and its subdirectories left empty,
+/// leaving out what `ignore` and its own ignore file exclude.
+/// Also returns its (device, inode) pair, the rules for its subdirectories
+/// and the failures to read its entries.
+async fn list_dir(
+    scan: &Arc<Scan>,
+    root: &Path,
+    rel: &Path,
+    ancestors: &Arc<Vec<(u64, u64)>>,
+    ignore: &Arc<Ignore>,
+) -> io::Result<(FnodeDir, (u64, u64), Arc<Ignore>, SyncReport)> {
+    let (dir, rel) = (root.join(rel), rel.to_path_buf());
+    let listing = read_listing(dir, rel, ancestors.clone(), ignore.clone(), scan.clone());
+    let Listing {
+        attrs,
+        id,
+        entries,
+        skipped,
+        failures,
+        ignore,
+    } = listing.await?;
+    let mut report = SyncReport::default();
+    for (path, e) in failures {
+        scan.fail(&mut report, path, e);
+    }
+    let mut dir = FnodeDir::default();
+    dir.set_attrs(attrs);
+    dir.set_skipped(skipped);
+    for entry in entries {
+        match entry {
+            Scanned::Node(name, node) => dir.append(name, Arc::new(node)),
+            Scanned::Dir(name, _) => dir.append_dir(name, FnodeDir::default()),
+        }
+    }
+    Ok((dir, id, ignore, report))
+}
+
+/// Split a diff of one directory into its files and links,
+/// and the names of its subdirectories along with whether they are entire.
+fn split(diff: &FnodeDir) -> (FnodeDir, Vec<(OsString, bool)>) {
+    let mut here = FnodeDir::default();
+    let mut dirs = vec![];
+    for (n, c) in diff.children() {
+        match c.as_ref() {
+            Fnode::Dir(d) => dirs.push((n.clone(), d.entirity())),
+            Fnode::File(_) | Fnode::Link(_) => here.append(n.clone(), c.clone()),
+        }
+    }
+    (here, dirs)
+}
+
+/// Sync the directory at `rel`, then its subdirectories one at a time, so that
+/// only the listings of the directories along the current path are held at once.
+/// `ancestors` are the (device, inode) pairs of the source directories above it,
+/// and `ignores` the rules of the source and destination directories above it.
+fn stream_dir(
+    rel: PathBuf,
+    walk: Walk,
+    ancestors: Arc<Vec<(u64, u64)>>,
+    ignores: (Arc<Ignore>, Arc<Ignore>),
+    stream: Arc<Stream>,
+) -> BoxFuture<'static, SyncReport> {
+    async move {
+        let ctx = &stream.ctx;
+        let dry_run = ctx.options.dry_run;
+        let mut report = SyncReport::default();
+        let mut whole = FnodeDir::default();
+        whole.set_entirity(true);
+        if walk == Walk::Create {
+            if dry_run {
+                plan_apply_dir(&whole, &rel, None, false, ctx, &mut report);
+            } else {
+                let created = apply_diff_node(
+                    Arc::new(Fnode::Dir(whole.clone())),
+                    rel.clone(),
+                    ctx.clone(),
+                    false,
+                )
+                .await;
+                let failed = !created.is_complete();
+                report.merge(created);
+                if failed {
+                    return report;
+                }
+            }
+        }
+
+        let list_src = async {
+            match walk {
+                Walk::Remove => Ok((
+                    FnodeDir::default(),
+                    (0, 0),
+                    ignores.0.clone(),
+                    SyncReport::default(),
+                )),
+                _ => {
+                    let (scan, ignore) = (&stream.src, &ignores.0);
+                    list_dir(scan, &ctx.src, &rel, &ancestors, ignore).await
+                }
+            }
+        };
+        let list_dest = async {
+            match walk {
+                Walk::Create => Ok((
+                    FnodeDir::default(),
+                    (0, 0),
+                    ignores.1.clone(),
+                    SyncReport::default(),
+                )),
+                _ => {
+                    let (scan, ignore) = (&stream.dest, &ignores.1);
+                    list_dir(scan, &ctx.dest, &rel, &Arc::new(vec![]), ignore).await
+                }
+            }
+        };
+        let (src, dest, id, ignores) = match tokio::join!(list_src, list_dest) {
+            (Ok((src, id, src_ignore, src_read)), Ok((dest, _, dest_ignore, dest_read))) => {
+                report.merge(src_read);
+                report.merge(dest_read);
+                (src, dest, id, (src_ignore, dest_ignore))
+            }
+            (Err(e), _) => {
+                ctx.fail(&mut report, ctx.src.join(&rel), e);
+                return report;
+            }
+            (_, Err(e)) => {
+                ctx.fail(&mut report, ctx.dest.join(&rel), e);
+                return report;
+            }
+        };
+        let (add, rem) = match (walk, ctx.options.mode) {
+            (Walk::Both, SyncMode::Soft) => calc_diff_soft(&src, &dest, false),
+            (Walk::Both, SyncMode::Mixed) => calc_diff_soft(&src, &dest, true),
+            (Walk::Both, SyncMode::Update) => (calc_diff_update(&src, &dest), FnodeDir::default()),
+            _ => calc_diff_hard(&src, &dest),
+        };
+        let (add_here, add_dirs) = split(&add);
+        let (rem_here, rem_dirs) = split(&rem);
+        stream.tally(&src, &dest, &add_here);
+        let mut ancestors = ancestors.as_ref().clone();
+        ancestors.push(id);
+        let ancestors = Arc::new(ancestors);
+
+        // removals come first, to make room for entries changing kind
+        if dry_run {
+            plan_remove_dir(&rem_here, &rel, false, ctx, &mut report);
+        } else {
+            let removed = Arc::new(Fnode::Dir(rem_here));
+            report.merge(remove_diff_node(removed, rel.clone(), ctx.clone(), false).await);
+        }
+        for (n, entire) in rem_dirs {
+            if entire && !ctx.protected(&mut report, &rel.join(&n), true) {
+                let (path, ignores) = (rel.join(n), ignores.clone());
+                let sub = stream_dir(
+                    path,
+                    Walk::Remove,
+                    ancestors.clone(),
+                    ignores,
+                    stream.clone(),
+                );
+                report.merge(sub.await);
+            }
+        }
+        if dry_run {
+            plan_apply_dir(&add_here, &rel, Some(&dest), false, ctx, &mut report);
+        } else {
+            let added = Arc::new(Fnode::Dir(add_here));
+            report.merge(apply_diff_node(added, rel.clone(), ctx.clone(), false).await);
+        }
+        for (n, entire) in add_dirs {
+            let walk = if entire { Walk::Create } else { Walk::Both };
+            let (path, ignores) = (rel.join(n), ignores.clone());
+            let sub = stream_dir(path, walk, ancestors.clone(), ignores, stream.clone());
+            report.merge(sub.await);
+        }
+
+        let dest_path = ctx.dest.join(&rel);
+        match walk {
+            // a directory holding protected entries stays
+            Walk::Remove if !report.protected.is_empty() => {}
+            Walk::Remove if dry_run => plan_remove_dir(&whole, &rel, false, ctx, &mut report),
+            Walk::Remove => {
+                let removed = Arc::new(Fnode::Dir(whole));
+                report.merge(remove_diff_node(removed, rel, ctx.clone(), false).await);
+            }
+            _ => {
+                if let (true, false, Some(attrs)) = (ctx.options.archive, dry_run, src.attrs()) {
+                    if let Err(e) = set_attrs(&dest_path, attrs) {
+                        ctx.fail(&mut report, dest_path, e);
+                    }
+                }
+            }
+        }
+        report
+    }
+    .boxed()
+}
+
+/// Sync `src` into `dest` one directory at a time instead of scanning both trees whole.
+/// Hard links are not detected.
+pub(crate) async fn sync_stream(
+    src: &Path,
+    dest: &Path,
+    src_ignore: Option<&[u8]>,
+    dest_ignore: Option<&[u8]>,
+    options: &SyncOptions,
+) -> Result<SyncReport, SyncError> {
+    let is_dir = |path: &Path| {
+        std::fs::metadata(path)
+            .map(|md| md.is_dir())
+            .unwrap_or(false)
+    };
+    if !is_dir(src) {
+        return Err(SyncError::Source);
+    }
+    if !is_dir(dest) {
+        return Err(SyncError::Destination);
+    }
+    let src_scan = Scan::new(src, options.links, options.limits, options).await;
+    let dest_scan = Scan::new(dest, LinkPolicy::Preserve, Limits::default(), options).await;
+    let stream = Arc::new(Stream {
+        ctx: Arc::new(Context::new(options, src, dest)),
+        src: Arc::new(src_scan.ok_or(SyncError::Source)?),
+        dest: Arc::new(dest_scan.ok_or(SyncError::Destination)?),
+        totals: Mutex::default(),
+    });
+    let ignores = (
+        ignore_rules(src, dest, src_ignore, options),
+        ignore_rules(dest, dest, dest_ignore, options),
+    );
+    // both trees are scanned along with the sync, which the scan events span
+    options.emit(SyncEvent::ScanStarted(src.to_path_buf()));
+    options.emit(SyncEvent::ScanStarted(dest.to_path_buf()));
+    let root = PathBuf::new();
+    let report = stream_dir(root, Walk::Both, Arc::new(vec![]), ignores, stream.clone()).await;
+    let totals = stream.totals.lock().unwrap_or_else(|e| e.into_inner());
+    for (root, (files, bytes)) in [(src, totals.src), (dest, totals.dest)] {
+        let root = root.to_path_buf();
+        options.emit(SyncEvent::ScanFinished { root, files, bytes });
+    }
+    Ok(report)
+}
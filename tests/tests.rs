@@ -1,6 +1,16 @@
-use std::path::PathBuf;
+use std::{
+    ffi::{OsStr, OsString},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::PathBuf,
+};
 
-use arsync::{sync_dirs, SyncMode};
+use arsync::{
+    prune_snapshots, sync_dirs, ConflictPolicy, Filter, Limits, LinkPolicy, Resolution, Retention,
+    SyncError, SyncEvent, SyncMode, SyncOptions,
+};
 
 struct TestDir {
     path: PathBuf,
@@ -26,6 +36,16 @@ impl TestDir {
         self.push(path, None)
     }
 
+    fn pushl(&self, path: &str, target: &str) {
+        let path = self.path.join(PathBuf::from(path));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(target, path).unwrap();
+    }
+
+    fn link(&self, path: &str) -> Option<PathBuf> {
+        std::fs::read_link(self.path.join(PathBuf::from(path))).ok()
+    }
+
     fn file_c(&self, path: &str, content: &str) -> bool {
         let path = self.path.join(PathBuf::from(path));
         match std::fs::read_to_string(path) {
@@ -56,7 +76,7 @@ impl TestDir {
         let path = loop {
             let num: u32 = rand::random();
             let path = format!("case{}", num);
-            if let Ok(_) = std::fs::create_dir(tmp.join(&path)) {
+            if std::fs::create_dir(tmp.join(&path)).is_ok() {
                 break path;
             }
         };
@@ -65,6 +85,23 @@ impl TestDir {
         }
     }
 
+    fn set_mtime(&self, path: &str, secs: u64) {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        let file = std::fs::File::options()
+            .write(true)
+            .open(self.relative(path))
+            .unwrap();
+        file.set_modified(time).unwrap();
+    }
+
+    fn mtime(&self, path: &str) -> u64 {
+        let md = std::fs::metadata(self.relative(path)).unwrap();
+        let time = md.modified().unwrap();
+        time.duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
     fn relative(&self, path: &str) -> PathBuf {
         self.path.join(PathBuf::from(path))
     }
@@ -76,8 +113,16 @@ impl Drop for TestDir {
     }
 }
 
+fn options(mode: SyncMode) -> SyncOptions {
+    SyncOptions {
+        mode,
+        verbose: true,
+        ..Default::default()
+    }
+}
+
 async fn test_sync_dir(src: PathBuf, dest: PathBuf, mode: SyncMode) {
-    sync_dirs(&src, &dest, None, None, true, mode)
+    sync_dirs(&src, &dest, None, None, &options(mode))
         .await
         .unwrap();
 }
@@ -92,10 +137,9 @@ async fn test_sync_dir_ignore(
     sync_dirs(
         &src,
         &dest,
-        Some(String::from(src_ignore)),
-        Some(String::from(dest_ignore)),
-        true,
-        mode,
+        Some(src_ignore.as_bytes().to_vec()),
+        Some(dest_ignore.as_bytes().to_vec()),
+        &options(mode),
     )
     .await
     .unwrap();
@@ -369,3 +413,1266 @@ async fn dest_ingore_subdir() {
     assert!(test_dir.file_c("dest/c", "cc"));
     assert!(test_dir.count("dest/") == 3);
 }
+
+#[tokio::test]
+async fn src_ignore_patterns() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a.o", "ac");
+    test_dir.pushf("src/keep.o", "kc");
+    test_dir.pushf("src/b.rs", "bc");
+    test_dir.pushf("src/x/c.o", "cc");
+    test_dir.pushf("src/x/node_modules/m", "mc");
+    test_dir.pushf("src/node_modules", "nc");
+    test_dir.pushf("src/build/b1", "b1c");
+    test_dir.pushf("src/x/build/b2", "b2c");
+    test_dir.pushf("src/f1", "f1c");
+    test_dir.pushf("src/f2", "f2c");
+    test_dir.pushf("src/fa", "fac");
+    test_dir.pushf("src/#c", "hc");
+    // dest
+    test_dir.pushd("dest");
+
+    test_sync_dir_ignore(
+        test_dir.relative("src"),
+        test_dir.relative("dest"),
+        SyncMode::Mixed,
+        "# objects\n*.o\n!keep.o\n**/node_modules/\n/build\nf[0-9]\n\\#c\n",
+        "",
+    )
+    .await;
+
+    assert!(test_dir.file_c("dest/keep.o", "kc"));
+    assert!(test_dir.file_c("dest/b.rs", "bc"));
+    assert!(test_dir.file_c("dest/node_modules", "nc"));
+    assert!(test_dir.file_c("dest/x/build/b2", "b2c"));
+    assert!(test_dir.file_c("dest/fa", "fac"));
+    assert!(!test_dir.file("dest/a.o"));
+    assert!(!test_dir.file("dest/x/c.o"));
+    assert!(!test_dir.dir("dest/x/node_modules"));
+    assert!(!test_dir.dir("dest/build"));
+    assert!(!test_dir.file("dest/f1"));
+    assert!(!test_dir.file("dest/#c"));
+    assert!(test_dir.count("dest/") == 5);
+}
+
+#[tokio::test]
+async fn nested_ignore_files() {
+    for streaming in [false, true] {
+        let test_dir = TestDir::acquire();
+        // src
+        test_dir.pushf("src/.arsygnore", "*.log\n");
+        test_dir.pushf("src/a.log", "ac");
+        test_dir.pushf("src/b", "bc");
+        test_dir.pushf("src/p/.arsygnore", "/out\n!keep.log\n");
+        test_dir.pushf("src/p/out/o", "oc");
+        test_dir.pushf("src/p/q/out/o2", "o2c");
+        test_dir.pushf("src/p/keep.log", "kc");
+        test_dir.pushf("src/p/x.log", "xc");
+        test_dir.pushf("src/r/out/o3", "o3c");
+        // dest
+        test_dir.pushd("dest");
+
+        let mut opts = options(SyncMode::Mixed);
+        opts.streaming = streaming;
+        sync_dirs(
+            &test_dir.relative("src"),
+            &test_dir.relative("dest"),
+            None,
+            None,
+            &opts,
+        )
+        .await
+        .unwrap();
+
+        assert!(test_dir.file_c("dest/b", "bc"));
+        assert!(test_dir.file_c("dest/p/keep.log", "kc"));
+        assert!(test_dir.file_c("dest/p/q/out/o2", "o2c"));
+        assert!(test_dir.file_c("dest/r/out/o3", "o3c"));
+        assert!(!test_dir.file("dest/a.log"));
+        assert!(!test_dir.file("dest/p/x.log"));
+        assert!(!test_dir.dir("dest/p/out"));
+        assert!(test_dir.count("dest/") == 4);
+        assert!(test_dir.count("dest/p") == 3);
+    }
+}
+
+#[tokio::test]
+async fn ignore_file_bytes() {
+    for streaming in [false, true] {
+        let test_dir = TestDir::acquire();
+        // src
+        test_dir.pushf("src/d/secret.key", "kc");
+        test_dir.pushf("src/d/b", "bc");
+        let latin1 = OsStr::from_bytes(b"caf\xe9");
+        std::fs::write(test_dir.relative("src/d").join(latin1), "cc").unwrap();
+        std::fs::write(test_dir.relative("src/d/.arsygnore"), b"caf\xe9\n*.key\n").unwrap();
+        // dest
+        test_dir.pushd("dest");
+
+        let mut opts = options(SyncMode::Hard);
+        opts.streaming = streaming;
+        sync_dirs(
+            &test_dir.relative("src"),
+            &test_dir.relative("dest"),
+            None,
+            None,
+            &opts,
+        )
+        .await
+        .unwrap();
+
+        assert!(test_dir.file_c("dest/d/b", "bc"));
+        assert!(!test_dir.file("dest/d/secret.key"));
+        assert!(!test_dir.relative("dest/d").join(latin1).exists());
+    }
+}
+
+#[tokio::test]
+async fn sync_filters() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/.arsygnore", "*.tmp\n");
+    test_dir.pushf("src/a.tmp", "ac");
+    test_dir.pushf("src/b.tmp", "bc");
+    test_dir.pushf("src/c.o", "cc");
+    test_dir.pushf("src/d.o", "dc");
+    test_dir.pushf("src/e", "ec");
+    // dest
+    test_dir.pushf("dest/f.o", "fc");
+    test_dir.pushf("dest/g", "gc");
+
+    let mut opts = options(SyncMode::Hard);
+    opts.filters = vec![
+        Filter::Exclude(OsString::from("*.o")),
+        Filter::Include(OsString::from("d.o")),
+        Filter::Include(OsString::from("a.tmp")),
+    ];
+    sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+
+    assert!(test_dir.file_c("dest/a.tmp", "ac"));
+    assert!(test_dir.file_c("dest/d.o", "dc"));
+    assert!(test_dir.file_c("dest/e", "ec"));
+    assert!(test_dir.file_c("dest/f.o", "fc"));
+    assert!(!test_dir.file("dest/b.tmp"));
+    assert!(!test_dir.file("dest/c.o"));
+    assert!(!test_dir.file("dest/g"));
+    assert!(test_dir.count("dest/") == 5);
+}
+
+#[tokio::test]
+async fn sync_protect() {
+    for streaming in [false, true] {
+        let test_dir = TestDir::acquire();
+        // src
+        test_dir.pushf("src/a", "ac");
+        test_dir.pushf("src/config.local", "new");
+        test_dir.pushd("src/s");
+        // dest
+        test_dir.pushf("dest/b", "bc");
+        test_dir.pushf("dest/config.local", "old");
+        test_dir.set_mtime("dest/config.local", 1000);
+        test_dir.pushf("dest/.git/HEAD", "hc");
+        test_dir.pushf("dest/old/keep.local", "kc");
+        test_dir.pushf("dest/old/x", "xc");
+        test_dir.pushf("dest/s/.git/HEAD", "shc");
+
+        let mut opts = options(SyncMode::Hard);
+        opts.streaming = streaming;
+        opts.filters = vec![
+            Filter::Protect(OsString::from("*.local")),
+            Filter::Protect(OsString::from(".git/")),
+        ];
+        let report = sync_dirs(
+            &test_dir.relative("src"),
+            &test_dir.relative("dest"),
+            None,
+            None,
+            &opts,
+        )
+        .await
+        .unwrap();
+
+        assert!(report.is_complete());
+        assert!(test_dir.file_c("dest/a", "ac"));
+        assert!(test_dir.file_c("dest/config.local", "new"));
+        assert!(test_dir.file_c("dest/.git/HEAD", "hc"));
+        assert!(test_dir.file_c("dest/old/keep.local", "kc"));
+        assert!(test_dir.file_c("dest/s/.git/HEAD", "shc"));
+        assert!(!test_dir.file("dest/old/x"));
+        assert!(!test_dir.file("dest/b"));
+        assert_eq!(report.protected.len(), 3);
+    }
+}
+
+#[tokio::test]
+async fn sync_limits() {
+    for streaming in [false, true] {
+        let test_dir = TestDir::acquire();
+        // src
+        test_dir.pushf("src/big", "big content");
+        test_dir.pushf("src/small", "sc");
+        test_dir.pushf("src/empty", "");
+        test_dir.pushf("src/old", "oc");
+        test_dir.set_mtime("src/old", 1000);
+        test_dir.pushf("src/d/big", "more big content");
+        // dest
+        test_dir.pushf("dest/big", "bc");
+        test_dir.pushf("dest/old", "ooc");
+        test_dir.pushf("dest/d/big", "dbc");
+        test_dir.pushf("dest/gone", "gc");
+
+        let mut opts = options(SyncMode::Hard);
+        opts.streaming = streaming;
+        opts.limits = Limits {
+            min_size: Some(1),
+            max_size: Some(4),
+            newer_than: Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(2000)),
+            older_than: None,
+        };
+        let report = sync_dirs(
+            &test_dir.relative("src"),
+            &test_dir.relative("dest"),
+            None,
+            None,
+            &opts,
+        )
+        .await
+        .unwrap();
+
+        assert!(report.is_complete());
+        assert!(test_dir.file_c("dest/small", "sc"));
+        assert!(test_dir.file_c("dest/big", "bc"));
+        assert!(test_dir.file_c("dest/old", "ooc"));
+        assert!(test_dir.file_c("dest/d/big", "dbc"));
+        assert!(!test_dir.file("dest/empty"));
+        assert!(!test_dir.file("dest/gone"));
+        assert!(test_dir.count("dest/") == 4);
+    }
+}
+
+#[tokio::test]
+async fn dry_run() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac+");
+    test_dir.pushf("src/b/b1", "b1c");
+    // dest
+    test_dir.pushf("dest/a", "ac");
+    test_dir.pushf("dest/c", "cc");
+
+    sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &SyncOptions {
+            mode: SyncMode::Hard,
+            dry_run: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    assert!(test_dir.file_c("dest/a", "ac"));
+    assert!(test_dir.file_c("dest/c", "cc"));
+    assert!(!test_dir.dir("dest/b"));
+    assert!(test_dir.count("dest/") == 2);
+}
+
+#[tokio::test]
+async fn sync_report() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac+");
+    test_dir.pushf("src/b/b1", "b1c");
+    // dest
+    test_dir.pushf("dest/a", "ac");
+    test_dir.pushf("dest/c/c1", "c1c");
+
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &options(SyncMode::Hard),
+    )
+    .await
+    .unwrap();
+
+    assert!(report.is_complete());
+    assert_eq!(report.copied.len(), 2);
+    assert_eq!(report.bytes, 6);
+    assert_eq!(report.created_dirs, vec![test_dir.relative("dest/b")]);
+    assert_eq!(report.removed_files, vec![test_dir.relative("dest/c/c1")]);
+    assert_eq!(report.removed_dirs, vec![test_dir.relative("dest/c")]);
+}
+
+#[tokio::test]
+async fn sync_checksum() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac+");
+    test_dir.pushf("src/b", "bc+");
+    test_dir.set_mtime("src/a", 1000);
+    test_dir.set_mtime("src/b", 2000);
+    // dest
+    test_dir.pushf("dest/a", "ac-");
+    test_dir.pushf("dest/b", "bc+");
+    test_dir.set_mtime("dest/a", 1000);
+    test_dir.set_mtime("dest/b", 1000);
+
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &SyncOptions {
+            checksum: true,
+            ..options(SyncMode::Mixed)
+        },
+    )
+    .await
+    .unwrap();
+
+    assert!(test_dir.file_c("dest/a", "ac+"));
+    assert_eq!(report.copied, vec![test_dir.relative("dest/a")]);
+}
+
+#[tokio::test]
+async fn sync_archive() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac");
+    test_dir.pushf("src/d/d1", "d1c");
+    test_dir.set_mtime("src/a", 1000);
+    test_dir.set_mtime("src/d/d1", 2000);
+    std::fs::set_permissions(
+        test_dir.relative("src/a"),
+        std::fs::Permissions::from_mode(0o640),
+    )
+    .unwrap();
+    filetime::set_file_mtime(
+        test_dir.relative("src/d"),
+        filetime::FileTime::from_unix_time(3000, 0),
+    )
+    .unwrap();
+    // dest
+    test_dir.pushd("dest");
+
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &SyncOptions {
+            archive: true,
+            ..options(SyncMode::Mixed)
+        },
+    )
+    .await
+    .unwrap();
+
+    assert!(report.is_complete());
+    assert_eq!(test_dir.mtime("dest/a"), 1000);
+    assert_eq!(test_dir.mtime("dest/d/d1"), 2000);
+    assert_eq!(test_dir.mtime("dest/d"), 3000);
+    let mode = std::fs::metadata(test_dir.relative("dest/a"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o640);
+}
+
+async fn test_sync_links(test_dir: &TestDir, links: LinkPolicy) {
+    test_dir.pushf("src/a", "ac");
+    test_dir.pushf("src/d/d1", "d1c");
+    test_dir.pushf("outside", "oc");
+    test_dir.pushl("src/la", "a");
+    test_dir.pushl("src/ld", "d");
+    test_dir.pushl("src/lo", "../outside");
+    let abs = test_dir.relative("src/a");
+    test_dir.pushl("src/labs", abs.to_str().unwrap());
+    test_dir.pushd("dest");
+
+    sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &SyncOptions {
+            links,
+            ..options(SyncMode::Hard)
+        },
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn sync_links_skip() {
+    let test_dir = TestDir::acquire();
+    test_sync_links(&test_dir, LinkPolicy::Skip).await;
+
+    assert!(test_dir.count("dest/") == 2);
+}
+
+#[tokio::test]
+async fn sync_links_preserve() {
+    let test_dir = TestDir::acquire();
+    test_sync_links(&test_dir, LinkPolicy::Preserve).await;
+
+    assert_eq!(test_dir.link("dest/la"), Some(PathBuf::from("a")));
+    assert_eq!(test_dir.link("dest/ld"), Some(PathBuf::from("d")));
+    assert_eq!(test_dir.link("dest/lo"), Some(PathBuf::from("../outside")));
+}
+
+#[tokio::test]
+async fn sync_links_follow() {
+    let test_dir = TestDir::acquire();
+    test_sync_links(&test_dir, LinkPolicy::Follow).await;
+
+    assert!(test_dir.link("dest/la").is_none());
+    assert!(test_dir.file_c("dest/la", "ac"));
+    assert!(test_dir.file_c("dest/ld/d1", "d1c"));
+    assert!(test_dir.file_c("dest/lo", "oc"));
+    assert!(test_dir.file_c("dest/labs", "ac"));
+}
+
+#[tokio::test]
+async fn sync_links_internal() {
+    let test_dir = TestDir::acquire();
+    test_sync_links(&test_dir, LinkPolicy::Internal).await;
+
+    assert_eq!(test_dir.link("dest/la"), Some(PathBuf::from("a")));
+    assert_eq!(test_dir.link("dest/ld"), Some(PathBuf::from("d")));
+    assert!(test_dir.link("dest/lo").is_none());
+    // an absolute target would still point into the source
+    assert!(test_dir.link("dest/labs").is_none());
+    assert!(test_dir.count("dest/") == 4);
+}
+
+#[tokio::test]
+async fn sync_links_replace() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac+");
+    test_dir.pushl("src/l", "a");
+    // dest
+    test_dir.pushf("dest/target", "tc");
+    test_dir.pushl("dest/a", "target");
+    test_dir.pushl("dest/l", "target");
+
+    sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &SyncOptions {
+            links: LinkPolicy::Preserve,
+            ..options(SyncMode::Mixed)
+        },
+    )
+    .await
+    .unwrap();
+
+    assert!(test_dir.link("dest/a").is_none());
+    assert!(test_dir.file_c("dest/a", "ac+"));
+    assert!(test_dir.file_c("dest/target", "tc"));
+    assert_eq!(test_dir.link("dest/l"), Some(PathBuf::from("a")));
+}
+
+#[tokio::test]
+async fn sync_hard_links() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac");
+    test_dir.pushd("src/d");
+    std::fs::hard_link(test_dir.relative("src/a"), test_dir.relative("src/b")).unwrap();
+    std::fs::hard_link(test_dir.relative("src/a"), test_dir.relative("src/d/c")).unwrap();
+    // dest
+    test_dir.pushd("dest");
+
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &SyncOptions {
+            hard_links: true,
+            ..options(SyncMode::Mixed)
+        },
+    )
+    .await
+    .unwrap();
+
+    let inode = |path: &str| std::fs::metadata(test_dir.relative(path)).unwrap().ino();
+    assert!(report.is_complete());
+    assert_eq!(report.copied.len(), 1);
+    assert_eq!(report.hard_linked.len(), 2);
+    assert_eq!(inode("dest/a"), inode("dest/b"));
+    assert_eq!(inode("dest/a"), inode("dest/d/c"));
+    assert!(test_dir.file_c("dest/d/c", "ac"));
+}
+
+#[tokio::test]
+async fn sync_bidirectional_hard_links() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac");
+    std::fs::hard_link(test_dir.relative("src/a"), test_dir.relative("src/b")).unwrap();
+    // dest
+    test_dir.pushf("dest/x", "xc");
+    std::fs::hard_link(test_dir.relative("dest/x"), test_dir.relative("dest/y")).unwrap();
+
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &SyncOptions {
+            hard_links: true,
+            ..options(SyncMode::Bidirectional)
+        },
+    )
+    .await
+    .unwrap();
+
+    let inode = |path: &str| std::fs::metadata(test_dir.relative(path)).unwrap().ino();
+    assert!(report.is_complete());
+    assert_eq!(report.copied.len(), 2);
+    assert_eq!(report.hard_linked.len(), 2);
+    assert_eq!(inode("dest/a"), inode("dest/b"));
+    assert_eq!(inode("src/x"), inode("src/y"));
+}
+
+#[tokio::test]
+async fn sync_atomic_replace() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac+");
+    test_dir.pushf("src/d/d1", "d1c");
+    // dest
+    test_dir.pushf("dest/a", "ac");
+    std::fs::hard_link(test_dir.relative("dest/a"), test_dir.relative("dest/b")).unwrap();
+
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &SyncOptions {
+            fsync: true,
+            partial_dir: Some(PathBuf::from(".partial")),
+            ..options(SyncMode::Mixed)
+        },
+    )
+    .await
+    .unwrap();
+
+    assert!(report.is_complete());
+    assert!(test_dir.file_c("dest/a", "ac+"));
+    assert!(test_dir.file_c("dest/b", "ac"));
+    assert!(test_dir.file_c("dest/d/d1", "d1c"));
+    assert!(test_dir.count("dest/") == 3);
+    assert!(test_dir.count("dest/d") == 1);
+}
+
+#[tokio::test]
+async fn sync_partial_dir_elsewhere() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac");
+    // dest
+    test_dir.pushd("dest");
+
+    // files could not be renamed out of a partial directory on another file system
+    let shm = PathBuf::from("/dev/shm");
+    let dev = |path: &PathBuf| std::fs::metadata(path).map(|md| md.dev()).ok();
+    if dev(&shm).is_none() || dev(&shm) == dev(&test_dir.relative("dest")) {
+        return;
+    }
+    let result = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &SyncOptions {
+            partial_dir: Some(shm.join("arsync-partial")),
+            ..options(SyncMode::Mixed)
+        },
+    )
+    .await;
+
+    assert!(matches!(result, Err(SyncError::PartialDir)));
+    assert!(!test_dir.file("dest/a"));
+}
+
+#[tokio::test]
+async fn sync_unreadable_entries() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac");
+    test_dir.pushl("src/d/sub", "missing");
+    // dest
+    test_dir.pushf("dest/d/sub/precious", "pc");
+
+    for (mode, streaming) in [
+        (SyncMode::Hard, false),
+        (SyncMode::Hard, true),
+        (SyncMode::Bidirectional, false),
+    ] {
+        let report = sync_dirs(
+            &test_dir.relative("src"),
+            &test_dir.relative("dest"),
+            None,
+            None,
+            &SyncOptions {
+                links: LinkPolicy::Follow,
+                streaming,
+                ..options(mode)
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].0, test_dir.relative("src/d/sub"));
+        assert!(test_dir.file_c("dest/a", "ac"));
+        assert!(test_dir.file_c("dest/d/sub/precious", "pc"));
+    }
+}
+
+#[tokio::test]
+async fn sync_bounded_jobs() {
+    let test_dir = TestDir::acquire();
+    // src
+    for d in 0..10 {
+        for f in 0..50 {
+            test_dir.pushf(&format!("src/d{}/f{}", d, f), "fc");
+        }
+    }
+    // dest
+    test_dir.pushf("dest/old/o1", "o1c");
+
+    let (events, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let mut opts = options(SyncMode::Hard);
+    opts.jobs = 2;
+    opts.events = Some(events);
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+    drop(opts);
+
+    assert!(report.is_complete());
+    assert_eq!(report.copied.len(), 500);
+    assert!(test_dir.count("dest/") == 10);
+    assert!(test_dir.count("dest/d9") == 50);
+
+    // events are sent while the operation holds its job
+    let (mut running, mut most) = (0, 0);
+    while let Some(event) = receiver.recv().await {
+        match event {
+            SyncEvent::FileStarted { .. } => running += 1,
+            SyncEvent::FileFinished { .. } => running -= 1,
+            _ => {}
+        }
+        most = most.max(running);
+    }
+    assert_eq!(running, 0);
+    assert!(most > 0 && most <= 2);
+}
+
+async fn test_sync_bidi(test_dir: &TestDir) {
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &options(SyncMode::Bidirectional),
+    )
+    .await
+    .unwrap();
+    assert!(report.is_complete());
+}
+
+#[tokio::test]
+async fn sync_bidirectional() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac");
+    test_dir.pushf("src/b", "bc");
+    test_dir.pushf("src/d/d1", "d1c");
+    // dest
+    test_dir.pushf("dest/c", "cc");
+    test_dir.pushf("dest/e/e1", "e1c");
+
+    test_sync_bidi(&test_dir).await;
+
+    for side in ["src", "dest"] {
+        assert!(test_dir.file_c(&format!("{}/a", side), "ac"));
+        assert!(test_dir.file_c(&format!("{}/c", side), "cc"));
+        assert!(test_dir.file_c(&format!("{}/d/d1", side), "d1c"));
+        assert!(test_dir.file_c(&format!("{}/e/e1", side), "e1c"));
+    }
+
+    // changes on either side since the last sync
+    std::fs::remove_file(test_dir.relative("src/a")).unwrap();
+    std::fs::remove_dir_all(test_dir.relative("dest/d")).unwrap();
+    test_dir.pushf("dest/b", "bc+");
+    test_dir.pushf("dest/e/e2", "e2c");
+    test_dir.pushf("src/f", "fc");
+
+    test_sync_bidi(&test_dir).await;
+
+    for side in ["src", "dest"] {
+        assert!(!test_dir.file(&format!("{}/a", side)));
+        assert!(!test_dir.dir(&format!("{}/d", side)));
+        assert!(test_dir.file_c(&format!("{}/b", side), "bc+"));
+        assert!(test_dir.file_c(&format!("{}/e/e2", side), "e2c"));
+        assert!(test_dir.file_c(&format!("{}/f", side), "fc"));
+    }
+}
+
+#[tokio::test]
+async fn sync_bidirectional_conflicts() {
+    let test_dir = TestDir::acquire();
+    test_dir.pushf("src/a", "ac");
+    test_dir.pushf("src/b", "bc");
+    test_dir.pushd("dest");
+
+    test_sync_bidi(&test_dir).await;
+
+    // both sides modify the same files
+    test_dir.pushf("src/a", "ac-src");
+    test_dir.pushf("dest/a", "ac-dest");
+    test_dir.pushf("src/b", "bc-src");
+    test_dir.pushf("dest/b", "bc-dest");
+
+    let mut opts = options(SyncMode::Bidirectional);
+    opts.conflicts = ConflictPolicy::Abort;
+    let result = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await;
+    match result {
+        Err(SyncError::Conflict(conflicts)) => {
+            assert_eq!(conflicts.len(), 2);
+            assert!(conflicts.iter().all(|c| c.resolution == Resolution::Abort));
+        }
+        _ => panic!("conflicts should abort the sync"),
+    }
+    assert!(test_dir.file_c("src/a", "ac-src"));
+    assert!(test_dir.file_c("dest/a", "ac-dest"));
+
+    opts.conflicts = ConflictPolicy::KeepBoth;
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+    assert!(report.is_complete());
+    assert_eq!(report.conflicts.len(), 2);
+    let copy = match &report.conflicts.iter().find(|c| c.path.as_os_str() == "a") {
+        Some(c) => match &c.resolution {
+            Resolution::KeepBoth(copy) => copy.clone(),
+            _ => panic!("both versions should be kept"),
+        },
+        None => panic!("a should conflict"),
+    };
+    for side in ["src", "dest"] {
+        assert!(test_dir.file_c(&format!("{}/a", side), "ac-src"));
+        assert!(test_dir.file_c(&format!("{}/{}", side, copy.display()), "ac-dest"));
+        assert!(test_dir.file_c(&format!("{}/b", side), "bc-src"));
+    }
+}
+
+#[tokio::test]
+async fn sync_bidirectional_unchanged() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac");
+    test_dir.pushf("src/d/d1", "d1c");
+    // dest
+    test_dir.pushf("dest/b", "bc");
+
+    let mut opts = options(SyncMode::Bidirectional);
+    opts.conflicts = ConflictPolicy::Abort;
+    sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+    // nothing changed on either side since
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+
+    assert!(report.is_complete());
+    assert!(report.conflicts.is_empty());
+    assert!(report.copied.is_empty());
+}
+
+#[tokio::test]
+async fn sync_bidirectional_retry() {
+    let test_dir = TestDir::acquire();
+    test_dir.pushf("src/sub/a", "ac");
+    test_dir.pushd("dest");
+
+    let mut opts = options(SyncMode::Bidirectional);
+    opts.partial_dir = Some(PathBuf::from(".partial"));
+    opts.filters = vec![Filter::Exclude(OsString::from(".partial"))];
+    test_sync_bidi(&test_dir).await;
+
+    // a file in the way of the partial directory makes the copy fail
+    test_dir.pushf("src/sub/new", "nc");
+    test_dir.pushf("dest/sub/.partial", "");
+    for _ in 0..2 {
+        let report = sync_dirs(
+            &test_dir.relative("src"),
+            &test_dir.relative("dest"),
+            None,
+            None,
+            &opts,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.failures.len(), 1);
+        assert!(!test_dir.file("dest/sub/new"));
+    }
+
+    std::fs::remove_file(test_dir.relative("dest/sub/.partial")).unwrap();
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+    assert!(report.is_complete());
+    assert!(test_dir.file_c("dest/sub/new", "nc"));
+    assert!(test_dir.file_c("src/sub/new", "nc"));
+}
+
+#[tokio::test]
+async fn sync_bidirectional_peers() {
+    let test_dir = TestDir::acquire();
+    test_dir.pushf("src/a", "ac");
+    test_dir.pushd("dest");
+    test_dir.pushd("peer");
+
+    let sync = |dest: &'static str| {
+        let (src, dest) = (test_dir.relative("src"), test_dir.relative(dest));
+        async move {
+            let opts = options(SyncMode::Bidirectional);
+            sync_dirs(&src, &dest, None, None, &opts).await.unwrap()
+        }
+    };
+    assert!(sync("dest").await.is_complete());
+    assert!(sync("peer").await.is_complete());
+
+    // a change from one peer reaches the other through src
+    test_dir.pushf("dest/b", "bc");
+    assert!(sync("dest").await.is_complete());
+    let report = sync("peer").await;
+    assert!(report.is_complete());
+    assert!(report.conflicts.is_empty());
+    assert!(test_dir.file_c("peer/a", "ac"));
+    assert!(test_dir.file_c("peer/b", "bc"));
+}
+
+#[tokio::test]
+async fn sync_backup_dir() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/d/a", "ac+");
+    test_dir.pushf("src/backup/c", "cc");
+    // dest
+    test_dir.pushf("dest/d/a", "ac");
+    test_dir.pushf("dest/b", "bc");
+    test_dir.set_mtime("dest/d/a", 1_000_000);
+
+    let mut opts = options(SyncMode::Hard);
+    opts.backup_dir = Some(PathBuf::from("backup"));
+    opts.backup_suffix = String::from("~");
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+
+    assert!(report.is_complete());
+    assert_eq!(report.backed_up.len(), 2);
+    assert!(test_dir.file_c("dest/d/a", "ac+"));
+    assert!(!test_dir.file("dest/b"));
+    assert!(test_dir.file_c("dest/backup/d/a~", "ac"));
+    assert!(test_dir.file_c("dest/backup/b~", "bc"));
+    // only the destination holds the backup directory
+    assert!(test_dir.file_c("dest/backup/c", "cc"));
+
+    // the backup directory is not part of the destination tree
+    sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+    assert!(test_dir.file_c("dest/backup/b~", "bc"));
+
+    // an absolute backup directory is left out of whichever tree holds it
+    test_dir.pushf("src/d/a", "ac++");
+    test_dir.pushf("src/old/e", "ec");
+    opts.backup_dir = Some(test_dir.relative("src/old"));
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+    assert!(report.is_complete());
+    assert!(test_dir.file_c("dest/d/a", "ac++"));
+    assert!(test_dir.file_c("src/old/d/a~", "ac+"));
+    assert!(!test_dir.dir("dest/old"));
+}
+
+#[tokio::test]
+async fn sync_link_dest() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac");
+    test_dir.pushf("src/d/b", "bc+");
+    test_dir.set_mtime("src/a", 1_000_000);
+    // previous snapshot
+    test_dir.pushf("prev/a", "ac");
+    test_dir.pushf("prev/d/b", "bc");
+    test_dir.set_mtime("prev/a", 1_000_000);
+    test_dir.pushd("dest");
+
+    let mut opts = options(SyncMode::Hard);
+    opts.link_dest = Some(PathBuf::from("../prev"));
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+
+    assert!(report.is_complete());
+    assert_eq!(report.hard_linked, vec![test_dir.relative("dest/a")]);
+    assert!(test_dir.file_c("dest/a", "ac"));
+    assert!(test_dir.file_c("dest/d/b", "bc+"));
+    assert!(test_dir.file_c("prev/d/b", "bc"));
+    let ino = |path: &str| std::fs::metadata(test_dir.relative(path)).unwrap().ino();
+    assert_eq!(ino("dest/a"), ino("prev/a"));
+    assert_ne!(ino("dest/d/b"), ino("prev/d/b"));
+}
+
+#[tokio::test]
+async fn prune_retention() {
+    let test_dir = TestDir::acquire();
+    for snapshot in [
+        "2022-03-01",
+        "2022-03-30",
+        "2022-04-03",
+        "2022-04-04T0900",
+        "2022-04-04T1800",
+        "2022-04-05",
+    ] {
+        test_dir.pushf(&format!("snaps/{}/d/a", snapshot), "ac");
+    }
+    test_dir.pushf("snaps/latest/a", "ac");
+    // ignore rules do not keep anything of a pruned snapshot
+    test_dir.pushf("snaps/2022-03-01/d/.arsygnore", "*.log\n");
+    test_dir.pushf("snaps/2022-03-01/d/b.log", "bc");
+    let fifo = test_dir.relative("snaps/2022-04-03/fifo");
+    let status = std::process::Command::new("mkfifo").arg(&fifo).status();
+    assert!(status.unwrap().success());
+
+    let retention = Retention {
+        daily: 2,
+        monthly: 2,
+        ..Default::default()
+    };
+    let mut opts = options(SyncMode::Hard);
+    opts.dry_run = true;
+    let report = prune_snapshots(&test_dir.relative("snaps"), &retention, &opts)
+        .await
+        .unwrap();
+    assert_eq!(report.removed_dirs.len(), 3);
+    assert!(test_dir.dir("snaps/2022-03-01"));
+
+    opts.dry_run = false;
+    let report = prune_snapshots(&test_dir.relative("snaps"), &retention, &opts)
+        .await
+        .unwrap();
+    assert!(report.is_complete());
+    for snapshot in ["2022-03-01", "2022-04-03", "2022-04-04T0900"] {
+        assert!(!test_dir.dir(&format!("snaps/{}", snapshot)));
+    }
+    for snapshot in ["2022-03-30", "2022-04-04T1800", "2022-04-05", "latest"] {
+        assert!(test_dir.dir(&format!("snaps/{}", snapshot)));
+    }
+}
+
+#[tokio::test]
+async fn sync_events() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac");
+    test_dir.pushf("src/d/b", "bcc");
+    let private = std::fs::Permissions::from_mode(0o600);
+    std::fs::set_permissions(test_dir.relative("src/a"), private).unwrap();
+    // dest
+    test_dir.pushf("dest/c", "cc");
+
+    let (events, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let mut opts = options(SyncMode::Hard);
+    opts.events = Some(events);
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+    assert!(report.is_complete());
+    let mode = std::fs::metadata(test_dir.relative("dest/a"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+    drop(opts);
+
+    let (mut scans, mut finished, mut removed, mut bytes) = (0, 0, 0, 0);
+    let mut planned = None;
+    while let Some(event) = receiver.recv().await {
+        match event {
+            SyncEvent::ScanFinished { .. } => scans += 1,
+            SyncEvent::Planned { files, bytes } => planned = Some((files, bytes)),
+            SyncEvent::FileFinished { bytes: b, .. } => {
+                finished += 1;
+                bytes += b;
+            }
+            SyncEvent::Removed(path) => {
+                assert_eq!(path, test_dir.relative("dest/c"));
+                removed += 1;
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(scans, 2);
+    assert_eq!(planned, Some((2, 5)));
+    assert_eq!((finished, bytes, removed), (2, 5, 1));
+}
+
+#[tokio::test]
+async fn sync_stream_events() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac");
+    test_dir.pushf("src/d/b", "bcc");
+    // dest
+    test_dir.pushf("dest/c", "cc");
+
+    let (events, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let mut opts = options(SyncMode::Hard);
+    opts.streaming = true;
+    opts.events = Some(events);
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+    assert!(report.is_complete());
+    drop(opts);
+
+    let (mut started, mut finished) = (0, 0);
+    let (mut scanned, mut planned) = (vec![], None);
+    while let Some(event) = receiver.recv().await {
+        match event {
+            SyncEvent::ScanStarted(_) => started += 1,
+            SyncEvent::ScanFinished { files, bytes, .. } => scanned.push((files, bytes)),
+            SyncEvent::Planned { files, bytes } => planned = Some((files, bytes)),
+            SyncEvent::FileFinished { .. } => finished += 1,
+            _ => {}
+        }
+    }
+    assert_eq!(started, 2);
+    assert_eq!(scanned, vec![(2, 5), (1, 2)]);
+    assert_eq!(planned, Some((2, 5)));
+    assert_eq!(finished, 2);
+}
+
+#[tokio::test]
+async fn sync_parallel_traversal() {
+    let test_dir = TestDir::acquire();
+    // src
+    for a in 0..4 {
+        for b in 0..4 {
+            for c in 0..4 {
+                test_dir.pushf(&format!("src/a{}/b{}/c{}/f", a, b, c), "fc");
+            }
+        }
+    }
+    // dest
+    test_dir.pushf("dest/a0/b0/c0/f", "fc");
+    test_dir.pushf("dest/a3/old", "oc");
+
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        None,
+        None,
+        &SyncOptions {
+            jobs: 1,
+            ..options(SyncMode::Hard)
+        },
+    )
+    .await
+    .unwrap();
+
+    assert!(report.is_complete());
+    assert_eq!(report.copied.len(), 63);
+    assert_eq!(report.removed_files, vec![test_dir.relative("dest/a3/old")]);
+    assert!(test_dir.count("dest/a3") == 4);
+    assert!(test_dir.file_c("dest/a3/b3/c3/f", "fc"));
+}
+
+#[tokio::test]
+async fn sync_non_utf8_names() {
+    let test_dir = TestDir::acquire();
+    let latin1 = OsStr::from_bytes(b"caf\xe9");
+    // src
+    test_dir.pushd("src");
+    let dir = test_dir.relative("src").join(latin1);
+    std::fs::create_dir(&dir).unwrap();
+    std::fs::write(dir.join("a"), "ac").unwrap();
+    std::fs::write(dir.join(latin1), "lc").unwrap();
+    // dest
+    test_dir.pushd("dest");
+    std::fs::write(
+        test_dir.relative("dest").join(OsStr::from_bytes(b"\xff")),
+        "xc",
+    )
+    .unwrap();
+
+    test_sync_dir(
+        test_dir.relative("src"),
+        test_dir.relative("dest"),
+        SyncMode::Hard,
+    )
+    .await;
+
+    let dir = test_dir.relative("dest").join(latin1);
+    assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"ac");
+    assert_eq!(std::fs::read(dir.join(latin1)).unwrap(), b"lc");
+    assert!(test_dir.count("dest") == 1);
+}
+
+#[tokio::test]
+async fn sync_streaming() {
+    let test_dir = TestDir::acquire();
+    // src
+    test_dir.pushf("src/a", "ac");
+    test_dir.pushf("src/d/e/f/g", "gc");
+    test_dir.pushf("src/k/k1", "k1c");
+    test_dir.pushf("src/m", "mc");
+    test_dir.pushf("src/s/ignored", "ic");
+    // dest
+    test_dir.pushf("dest/b/b1/b2", "b2c");
+    test_dir.pushf("dest/k", "kc");
+    test_dir.pushf("dest/m/m1", "m1c");
+    test_dir.pushf("dest/s/kept", "sc");
+
+    let mut opts = options(SyncMode::Hard);
+    opts.streaming = true;
+    opts.dry_run = true;
+    let plan = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        Some(b"s/ignored\n".to_vec()),
+        Some(b"s/kept\n".to_vec()),
+        &opts,
+    )
+    .await
+    .unwrap();
+    assert!(test_dir.dir("dest/b"));
+
+    opts.dry_run = false;
+    let report = sync_dirs(
+        &test_dir.relative("src"),
+        &test_dir.relative("dest"),
+        Some(b"s/ignored\n".to_vec()),
+        Some(b"s/kept\n".to_vec()),
+        &opts,
+    )
+    .await
+    .unwrap();
+
+    assert!(report.is_complete());
+    assert_eq!(plan.copied.len(), report.copied.len());
+    assert_eq!(plan.removed_files.len(), report.removed_files.len());
+    assert_eq!(plan.removed_dirs, report.removed_dirs);
+    assert_eq!(plan.created_dirs, report.created_dirs);
+    assert!(test_dir.file_c("dest/a", "ac"));
+    assert!(test_dir.file_c("dest/d/e/f/g", "gc"));
+    assert!(test_dir.file_c("dest/k/k1", "k1c"));
+    assert!(test_dir.file_c("dest/m", "mc"));
+    assert!(!test_dir.dir("dest/b"));
+    assert!(test_dir.file_c("dest/s/kept", "sc"));
+    assert!(!test_dir.file("dest/s/ignored"));
+}